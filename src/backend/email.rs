@@ -0,0 +1,116 @@
+use anyhow::Result;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use super::{Notifier, push};
+use crate::bark::Push;
+use crate::send::{Delivery, Envelope};
+
+/// Mailboxes behind an SMTP server, the title of a push is the subject
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Email {
+    /// Host of the SMTP server
+    host: String,
+
+    /// Default is 587 with STARTTLS, 465 with TLS and 25 without
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+
+    #[serde(default)]
+    security: Security,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+
+    /// Sender, like "barsk <barsk@example.com>"
+    from: String,
+
+    /// Recipients
+    to: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// Upgrade the connection with STARTTLS, fail if the server can't
+    #[default]
+    Starttls,
+    /// TLS from the start
+    Tls,
+    /// Plain text, for servers on the same host
+    None,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The push as plain text and HTML, the body is plain text and markdown in it is not rendered
+fn render(push: &Push) -> (String, String) {
+    let body = push.body().unwrap_or_default();
+    let mut text = body.to_owned();
+    let mut html = body
+        .split("\n\n")
+        .map(|paragraph| format!("<p>{}</p>", escape(paragraph).replace('\n', "<br>\n")))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if let Some(url) = push.url() {
+        text.push_str(&format!("\n\n{url}"));
+        html.push_str(&format!("\n<p><a href=\"{0}\">{0}</a></p>", escape(url)));
+    }
+    (text, html)
+}
+
+impl Email {
+    fn message(&self, push: &Push) -> Result<Message> {
+        let subject = push.title().map_or_else(|| push.label(), str::to_owned);
+        let mut builder = Message::builder()
+            .from(self.from.parse::<Mailbox>()?)
+            .subject(subject);
+        for to in &self.to {
+            builder = builder.to(to.parse::<Mailbox>()?);
+        }
+        let (text, html) = render(push);
+        Ok(builder.multipart(MultiPart::alternative_plain_html(text, html))?)
+    }
+
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+        let mut builder = match self.security {
+            Security::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.host)?,
+            Security::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.host)?,
+            Security::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host),
+        };
+        if let Some(port) = self.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(builder.build())
+    }
+}
+
+impl Notifier for Email {
+    async fn notify(
+        &self,
+        _: &Client,
+        envelope: &Envelope,
+        _: &str,
+        delivery: &mut Delivery,
+    ) -> Result<()> {
+        let message = self.message(push(envelope)?)?;
+        let resp = self.transport()?.send(message).await?;
+        delivery.code = Some(resp.code().to_string().parse()?);
+        delivery.message = Some(resp.message().collect::<Vec<_>>().join(" "));
+        Ok(())
+    }
+}
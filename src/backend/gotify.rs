@@ -0,0 +1,58 @@
+use anyhow::Result;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{Notifier, post, push, read_status};
+use crate::bark::Level;
+use crate::send::{Delivery, Envelope, endpoint};
+
+/// An application of a Gotify server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Gotify {
+    server: String,
+
+    /// Token of the application
+    token: String,
+}
+
+impl Gotify {
+    fn request(&self, client: &Client, envelope: &Envelope) -> Result<RequestBuilder> {
+        let push = push(envelope)?;
+        let priority = match push.level() {
+            Some(Level::Critical) => 10,
+            Some(Level::TimeSensitive) => 8,
+            Some(Level::Passive) => 1,
+            Some(Level::Active) | None => 5,
+        };
+        let mut message = json!({
+            "message": push.body().or(push.title()).unwrap_or_default(),
+            "priority": priority,
+        });
+        if let Some(title) = push.title().filter(|_| push.body().is_some()) {
+            message["title"] = title.into();
+        }
+        if let Some(url) = push.url() {
+            message["extras"] = json!({ "client::notification": { "click": { "url": url } } });
+        }
+
+        let url = endpoint(&self.server, "message")?;
+        Ok(client
+            .post(url)
+            .header("X-Gotify-Key", &self.token)
+            .json(&message))
+    }
+}
+
+impl Notifier for Gotify {
+    async fn notify(
+        &self,
+        client: &Client,
+        envelope: &Envelope,
+        _: &str,
+        delivery: &mut Delivery,
+    ) -> Result<()> {
+        let resp = post(self.request(client, envelope)?, delivery).await?;
+        read_status(resp, delivery).await
+    }
+}
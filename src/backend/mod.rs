@@ -0,0 +1,108 @@
+mod email;
+mod gotify;
+mod ntfy;
+mod pushover;
+mod webhook;
+
+use anyhow::Result;
+use reqwest::{Client, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+
+pub use email::Email;
+pub use gotify::Gotify;
+pub use ntfy::Ntfy;
+pub use pushover::Pushover;
+pub use webhook::Webhook;
+
+use crate::bark::{Push, Service};
+use crate::send::{Delivery, Envelope};
+
+/// A transport that delivers a push to one device
+pub trait Notifier {
+    /// Send the push of the envelope to the device, the outcome goes in the delivery
+    fn notify(
+        &self,
+        client: &Client,
+        envelope: &Envelope,
+        device: &str,
+        delivery: &mut Delivery,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// A device of another service than Bark, declared in the `devices` section
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum Backend {
+    Ntfy(Ntfy),
+    Gotify(Gotify),
+    Pushover(Pushover),
+    Webhook(Webhook),
+    Email(Email),
+}
+
+impl Backend {
+    /// The backend with its credentials looked up in the `devices` section
+    pub fn resolved(&self, service: &Service) -> Self {
+        match self {
+            Backend::Pushover(pushover) => Backend::Pushover(pushover.resolved(service)),
+            other => other.clone(),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Backend::Ntfy(_) => "ntfy",
+            Backend::Gotify(_) => "gotify",
+            Backend::Pushover(_) => "pushover",
+            Backend::Webhook(_) => "webhook",
+            Backend::Email(_) => "email",
+        }
+    }
+}
+
+impl Notifier for Backend {
+    async fn notify(
+        &self,
+        client: &Client,
+        envelope: &Envelope,
+        device: &str,
+        delivery: &mut Delivery,
+    ) -> Result<()> {
+        match self {
+            Backend::Ntfy(ntfy) => ntfy.notify(client, envelope, device, delivery).await,
+            Backend::Gotify(gotify) => gotify.notify(client, envelope, device, delivery).await,
+            Backend::Pushover(pushover) => {
+                pushover.notify(client, envelope, device, delivery).await
+            }
+            Backend::Webhook(webhook) => webhook.notify(client, envelope, device, delivery).await,
+            Backend::Email(email) => email.notify(client, envelope, device, delivery).await,
+        }
+    }
+}
+
+/// Send a request, its status goes in the delivery
+pub async fn post(request: RequestBuilder, delivery: &mut Delivery) -> Result<Response> {
+    let resp = request.send().await?;
+    delivery.status = Some(resp.status().as_u16());
+    Ok(resp)
+}
+
+/// The push itself, which other backends get instead of the sealed payload
+fn push(envelope: &Envelope) -> Result<&Push> {
+    envelope
+        .push
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("The push was sealed for Bark devices only"))
+}
+
+/// The status is the code, the body is the message of failures
+async fn read_status(resp: Response, delivery: &mut Delivery) -> Result<()> {
+    let status = resp.status();
+    delivery.code = Some(status.as_u16());
+    delivery.message = Some(if status.is_success() {
+        "success".to_owned()
+    } else {
+        resp.text().await?.trim().to_owned()
+    });
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use anyhow::Result;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{Notifier, post, push, read_status};
+use crate::bark::Level;
+use crate::send::{Delivery, Envelope};
+
+const NTFY_SERVER: &str = "https://ntfy.sh";
+
+/// A topic of an ntfy server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ntfy {
+    /// Default is https://ntfy.sh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    server: Option<String>,
+
+    topic: String,
+
+    /// Access token of the topic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl Ntfy {
+    fn request(&self, client: &Client, envelope: &Envelope) -> Result<RequestBuilder> {
+        let push = push(envelope)?;
+        let mut fields = Map::new();
+        fields.insert("topic".to_owned(), self.topic.clone().into());
+        // ntfy needs a message, a push with only a title sends it as the message
+        match (push.title(), push.body()) {
+            (Some(title), Some(body)) => {
+                fields.insert("title".to_owned(), title.into());
+                fields.insert("message".to_owned(), body.into());
+            }
+            (title, body) => {
+                fields.insert(
+                    "message".to_owned(),
+                    title.or(body).unwrap_or_default().into(),
+                );
+            }
+        }
+        let priority = match push.level() {
+            Some(Level::Critical) => 5,
+            Some(Level::TimeSensitive) => 4,
+            Some(Level::Passive) => 2,
+            Some(Level::Active) | None => 3,
+        };
+        fields.insert("priority".to_owned(), priority.into());
+        if let Some(url) = push.url() {
+            fields.insert("click".to_owned(), url.into());
+        }
+        if let Some(icon) = push.icon() {
+            fields.insert("icon".to_owned(), icon.into());
+        }
+        if let Some(group) = push.group() {
+            fields.insert("tags".to_owned(), vec![group].into());
+        }
+
+        let server = self.server.as_deref().unwrap_or(NTFY_SERVER);
+        let mut request = client.post(server).json(&Value::Object(fields));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        Ok(request)
+    }
+}
+
+impl Notifier for Ntfy {
+    async fn notify(
+        &self,
+        client: &Client,
+        envelope: &Envelope,
+        _: &str,
+        delivery: &mut Delivery,
+    ) -> Result<()> {
+        let resp = post(self.request(client, envelope)?, delivery).await?;
+        read_status(resp, delivery).await
+    }
+}
@@ -0,0 +1,267 @@
+use anyhow::Result;
+use reqwest::{Client, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{Notifier, post, push};
+use crate::bark::{Level, Service};
+use crate::send::{Delivery, Envelope, endpoint};
+
+const PUSHOVER_SERVER: &str = "https://api.pushover.net";
+
+/// A user of Pushover, or a device of the user
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Pushover {
+    /// Default is https://api.pushover.net
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    server: Option<String>,
+
+    /// Token of the application, or a name of the `devices` section that holds it
+    token: String,
+
+    /// Key of the user or group, or a name of the `devices` section that holds it
+    user: String,
+
+    /// Name of one device of the user, all of them if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device: Option<String>,
+
+    /// Seconds between the retries of a call, at least 30, default is 60
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry: Option<u32>,
+
+    /// Seconds until a call stops retrying, at most 10800, default is 3600
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expire: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Reply {
+    status: i32,
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+impl Pushover {
+    /// The credentials looked up in the `devices` section like device keys
+    pub fn resolved(&self, service: &Service) -> Self {
+        Self {
+            token: service.key(&self.token).to_owned(),
+            user: service.key(&self.user).to_owned(),
+            ..self.clone()
+        }
+    }
+
+    fn request(&self, client: &Client, envelope: &Envelope) -> Result<RequestBuilder> {
+        let push = push(envelope)?;
+        let mut fields = Map::new();
+        fields.insert("token".to_owned(), self.token.clone().into());
+        fields.insert("user".to_owned(), self.user.clone().into());
+        if let Some(device) = &self.device {
+            fields.insert("device".to_owned(), device.clone().into());
+        }
+        fields.insert(
+            "message".to_owned(),
+            push.body().or(push.title()).unwrap_or_default().into(),
+        );
+        if let Some(title) = push.title().filter(|_| push.body().is_some()) {
+            fields.insert("title".to_owned(), title.into());
+        }
+        if let Some(url) = push.url() {
+            fields.insert("url".to_owned(), url.into());
+        }
+        if let Some(sound) = push.sound() {
+            fields.insert("sound".to_owned(), sound.into());
+        }
+
+        // A call repeats until acknowledged, which is the emergency priority of Pushover
+        let priority = match push.level() {
+            _ if push.call() => 2,
+            Some(Level::Critical | Level::TimeSensitive) => 1,
+            Some(Level::Passive) => -1,
+            Some(Level::Active) | None => 0,
+        };
+        fields.insert("priority".to_owned(), priority.into());
+        if priority == 2 {
+            let retry = self.retry.unwrap_or(60).max(30);
+            let expire = self.expire.unwrap_or(3600).min(10800);
+            fields.insert("retry".to_owned(), retry.into());
+            fields.insert("expire".to_owned(), expire.into());
+        }
+
+        let server = self.server.as_deref().unwrap_or(PUSHOVER_SERVER);
+        let url = endpoint(server, "1/messages.json")?;
+        Ok(client.post(url).json(&Value::Object(fields)))
+    }
+
+    async fn read(&self, resp: Response, delivery: &mut Delivery) -> Result<()> {
+        delivery.code = Some(resp.status().as_u16());
+        let reply = resp.json::<Reply>().await?;
+        delivery.message = Some(if reply.status == 1 {
+            "success".to_owned()
+        } else {
+            reply.errors.join(", ")
+        });
+        if reply.status != 1 && delivery.is_success() {
+            delivery.code = Some(400);
+        }
+        Ok(())
+    }
+}
+
+impl Notifier for Pushover {
+    async fn notify(
+        &self,
+        client: &Client,
+        envelope: &Envelope,
+        _: &str,
+        delivery: &mut Delivery,
+    ) -> Result<()> {
+        let resp = post(self.request(client, envelope)?, delivery).await?;
+        self.read(resp, delivery).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use axum::{Json, Router, http::Uri};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    type Requests = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// A Pushover server on a local port that records the requests and answers with `reply`,
+    /// its url has a path to check that the endpoint goes under it
+    async fn mock(reply: Value) -> (String, Requests) {
+        let requests = Requests::default();
+        let recorded = requests.clone();
+        let app = Router::new().fallback(move |uri: Uri, Json(body): Json<Value>| {
+            let recorded = recorded.clone();
+            let reply = reply.clone();
+            async move {
+                recorded.lock().unwrap().push((uri.path().to_owned(), body));
+                Json(reply)
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (format!("http://{addr}/pushover"), requests)
+    }
+
+    fn pushover(server: &str, extra: Value) -> Pushover {
+        let mut config = json!({ "server": server, "token": "app", "user": "me" });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn envelope(push: Value) -> Envelope {
+        Envelope {
+            server: String::new(),
+            devices: vec!["watch".to_owned()],
+            payload: String::new(),
+            encrypted: false,
+            backends: HashMap::new(),
+            push: Some(serde_json::from_value(push).unwrap()),
+            transport: Default::default(),
+        }
+    }
+
+    async fn send(pushover: &Pushover, push: Value) -> Delivery {
+        let mut delivery = Delivery::new("watch".to_owned());
+        pushover
+            .notify(&Client::new(), &envelope(push), "watch", &mut delivery)
+            .await
+            .unwrap();
+        delivery
+    }
+
+    #[tokio::test]
+    async fn fields_of_the_push() {
+        let (server, requests) = mock(json!({ "status": 1 })).await;
+        let push = json!({
+            "title": "db",
+            "body": "disk full",
+            "level": "timeSensitive",
+            "url": "https://example.com/db",
+            "sound": "bell",
+        });
+        let delivery = send(&pushover(&server, json!({ "device": "iphone" })), push).await;
+        assert!(delivery.is_success());
+        assert_eq!(delivery.message.as_deref(), Some("success"));
+
+        let (path, body) = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(path, "/pushover/1/messages.json");
+        assert_eq!(
+            body,
+            json!({
+                "token": "app",
+                "user": "me",
+                "device": "iphone",
+                "title": "db",
+                "message": "disk full",
+                "url": "https://example.com/db",
+                "sound": "bell",
+                "priority": 1,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn calls_are_emergencies() {
+        let (server, requests) = mock(json!({ "status": 1 })).await;
+        send(
+            &pushover(&server, json!({})),
+            json!({ "body": "b", "call": "1" }),
+        )
+        .await;
+        send(
+            &pushover(&server, json!({ "retry": 10, "expire": 99999 })),
+            json!({ "body": "b", "call": "1", "level": "passive" }),
+        )
+        .await;
+
+        let requests = requests.lock().unwrap();
+        let fields = |i: usize| {
+            let body = &requests[i].1;
+            (
+                body["priority"].clone(),
+                body["retry"].clone(),
+                body["expire"].clone(),
+            )
+        };
+        assert_eq!(fields(0), (json!(2), json!(60), json!(3600)));
+        assert_eq!(fields(1), (json!(2), json!(30), json!(10800)));
+    }
+
+    #[tokio::test]
+    async fn errors_of_pushover() {
+        let reply = json!({ "status": 0, "errors": ["user key is invalid"] });
+        let (server, _) = mock(reply).await;
+        let delivery = send(&pushover(&server, json!({})), json!({ "body": "b" })).await;
+        assert!(!delivery.is_success());
+        assert_eq!(delivery.code, Some(400));
+        assert_eq!(delivery.message.as_deref(), Some("user key is invalid"));
+    }
+
+    #[test]
+    fn credentials_from_devices() {
+        let service = serde_json::from_value::<Service>(json!({
+            "devices": { "pushover-app": "azGD", "phone": "key" },
+        }))
+        .unwrap();
+        let resolved = pushover("", json!({ "token": "pushover-app" })).resolved(&service);
+        assert_eq!(
+            (resolved.token.as_str(), resolved.user.as_str()),
+            ("azGD", "me")
+        );
+    }
+}
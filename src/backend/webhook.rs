@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+
+use super::{Notifier, post, push, read_status};
+use crate::send::{Delivery, Envelope};
+
+/// Any url that takes the push as JSON, with the field names of Bark
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Webhook {
+    url: String,
+
+    /// Extra headers, e.g. for authorization
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<String, String>,
+}
+
+impl Webhook {
+    fn request(&self, client: &Client, envelope: &Envelope) -> Result<RequestBuilder> {
+        let mut request = client.post(&self.url).json(push(envelope)?);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        Ok(request)
+    }
+}
+
+impl Notifier for Webhook {
+    async fn notify(
+        &self,
+        client: &Client,
+        envelope: &Envelope,
+        _: &str,
+        delivery: &mut Delivery,
+    ) -> Result<()> {
+        let resp = post(self.request(client, envelope)?, delivery).await?;
+        read_status(resp, delivery).await
+    }
+}
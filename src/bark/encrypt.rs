@@ -14,7 +14,7 @@ type Aes128CbcEnc = cbc::Encryptor<Aes128Enc>;
 type Aes192CbcEnc = cbc::Encryptor<Aes192Enc>;
 type Aes256CbcEnc = cbc::Encryptor<Aes256Enc>;
 
-#[derive(Args, Deserialize, Debug)]
+#[derive(Args, Deserialize, Clone, Debug)]
 pub struct Encryption {
     /// Send encrypted push. Make sure not to use encryption, use --no-encryption, simple as -E
     #[arg(long, short = 'e', overrides_with = "no_encrypt")]
@@ -47,6 +47,11 @@ impl Encryption {
         self.encrypt
     }
 
+    /// Name of the encryption mode, if encryption is enabled
+    pub fn mode(&self) -> Option<&'static str> {
+        self.encrypt.then(|| self.modes.name())
+    }
+
     pub fn merge(&mut self, other: Self, no_encrypt: bool) {
         if !self.encrypt && !no_encrypt {
             self.encrypt = other.encrypt;
@@ -238,6 +243,19 @@ enum Modes {
     Aes256Ecb,
 }
 
+impl Modes {
+    fn name(self) -> &'static str {
+        match self {
+            Aes128Cbc => "aes128cbc",
+            Aes192Cbc => "aes192cbc",
+            Aes256Cbc => "aes256cbc",
+            Aes128Ecb => "aes128ecb",
+            Aes192Ecb => "aes192ecb",
+            Aes256Ecb => "aes256ecb",
+        }
+    }
+}
+
 impl<'de> de::Deserialize<'de> for Modes {
     fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
     where
@@ -1,10 +1,23 @@
 mod encrypt;
 
+use std::collections::HashMap;
+
 use clap::{ArgAction, Args, ValueEnum, value_parser};
 use serde::{Deserialize, Serialize, Serializer, de};
 
 pub use encrypt::Encryption;
 
+use crate::backend::Backend;
+use crate::escalate::Policy;
+use crate::heartbeat::HeartbeatConfig;
+use crate::quiet::QuietHours;
+use crate::route::Route;
+use crate::serve::{AlertmanagerConfig, ServeConfig, WebhookRule};
+use crate::smtp::SmtpConfig;
+use crate::syslog::SyslogConfig;
+use crate::watch_log::LogRule;
+use crate::watch_path::PathRule;
+
 #[derive(Deserialize, Debug)]
 pub struct Configuration {
     #[serde(flatten)]
@@ -15,9 +28,48 @@ pub struct Configuration {
 
     #[serde(flatten)]
     pub stored: Storable,
+
+    #[serde(flatten)]
+    pub sections: Sections,
 }
 
-#[derive(Deserialize, Args, Debug)]
+/// Configuration of the subcommands
+#[derive(Deserialize, Default, Debug)]
+pub struct Sections {
+    #[serde(default)]
+    pub serve: ServeConfig,
+
+    #[serde(default)]
+    pub alertmanager: AlertmanagerConfig,
+
+    /// Rules of `/hooks/<name>` by name
+    #[serde(default)]
+    pub webhooks: HashMap<String, Vec<WebhookRule>>,
+
+    #[serde(default)]
+    pub smtp: SmtpConfig,
+
+    #[serde(default)]
+    pub syslog: SyslogConfig,
+
+    /// Rules of `watch-log` by the path of the file
+    #[serde(default)]
+    pub watch_log: HashMap<String, Vec<LogRule>>,
+
+    /// Rules of `watch-path` by the directory
+    #[serde(default)]
+    pub watch_path: HashMap<String, Vec<PathRule>>,
+
+    /// Heartbeats by name
+    #[serde(default)]
+    pub heartbeats: HashMap<String, HeartbeatConfig>,
+
+    /// Escalation policies by name
+    #[serde(default)]
+    pub escalations: HashMap<String, Policy>,
+}
+
+#[derive(Deserialize, Args, Clone, Debug)]
 pub struct Service {
     /// The server address of bark api service, default is https://api.day.app
     #[arg(long, short = 's')]
@@ -40,10 +92,54 @@ pub struct Service {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     device_keys: Vec<String>,
 
+    /// How pushes reach the Bark server, get falls back to post if the url is too long
+    #[arg(long, value_enum)]
+    #[serde(default)]
+    transport: Option<Transport>,
+
     /// Use configured device keys, default not
     #[arg(long, short = 'k')]
     #[serde(skip)]
     use_file_key: bool,
+
+    /// Names of device keys, or of devices of other backends
+    #[arg(skip)]
+    #[serde(default)]
+    devices: HashMap<String, Device>,
+
+    /// Names of lists of devices
+    #[arg(skip)]
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+
+    /// Windows that downgrade or defer pushes
+    #[arg(skip)]
+    #[serde(default)]
+    quiet_hours: Vec<QuietHours>,
+
+    /// Rules that add devices by the level, group and tags of a push
+    #[arg(skip)]
+    #[serde(default)]
+    routes: Vec<Route>,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// JSON, or a form if encrypted, in the body of a POST request
+    #[default]
+    Post,
+    /// Fields in the path and the query of a GET request
+    Get,
+}
+
+/// A device of the `devices` section
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Device {
+    /// A Bark device key
+    Key(String),
+    Backend(Backend),
 }
 
 impl Service {
@@ -51,6 +147,9 @@ impl Service {
         if self.server.is_none() {
             self.server = other.server;
         }
+        if self.transport.is_none() {
+            self.transport = other.transport;
+        }
 
         if self.use_file_key || self.device_keys.is_empty() {
             if self.device_key.is_none() {
@@ -61,29 +160,101 @@ impl Service {
             self.device_keys.dedup();
         }
 
-        if let Some(key) = self.device_key.as_ref() {
-            if self.device_keys.contains(key) {
-                self.device_key = None;
-            }
+        if let Some(key) = self.device_key.as_ref()
+            && self.device_keys.contains(key)
+        {
+            self.device_key = None;
         }
+
+        for (name, key) in other.devices {
+            self.devices.entry(name).or_insert(key);
+        }
+        for (name, members) in other.groups {
+            self.groups.entry(name).or_insert(members);
+        }
+        self.quiet_hours.extend(other.quiet_hours);
+        self.routes.extend(other.routes);
+    }
+
+    /// The server if one is given, without the default
+    pub fn configured_server(&self) -> Option<&str> {
+        self.server.as_deref()
     }
 
     pub fn server(&self) -> &str {
         self.server.as_deref().unwrap_or(crate::API_SERVER)
     }
 
-    pub fn device_keys(&self) -> Vec<&str> {
-        let mut keys = self
-            .device_keys
-            .iter()
-            .map(|s| s.as_str())
-            .collect::<Vec<_>>();
-        keys.extend(self.device_key.as_deref());
+    pub fn transport(&self) -> Transport {
+        self.transport.unwrap_or_default()
+    }
+
+    pub fn devices(&self) -> &HashMap<String, Device> {
+        &self.devices
+    }
+
+    pub fn groups(&self) -> &HashMap<String, Vec<String>> {
+        &self.groups
+    }
+
+    pub fn quiet_hours(&self) -> &[QuietHours] {
+        &self.quiet_hours
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    pub fn device_keys(&self) -> Vec<String> {
+        self.resolve(self.device_keys.iter().chain(self.device_key.as_ref()))
+    }
+
+    /// The device key of a device name, other names are keys already
+    pub fn key<'a>(&'a self, name: &'a str) -> &'a str {
+        match self.devices.get(name) {
+            Some(Device::Key(key)) => key,
+            _ => name,
+        }
+    }
+
+    /// Whether a name is one of the device names or group names
+    pub fn knows(&self, name: &str) -> bool {
+        self.devices.contains_key(name) || self.groups.contains_key(name)
+    }
+
+    /// The backend of a device name, `None` for Bark devices
+    pub fn backend(&self, name: &str) -> Option<&Backend> {
+        match self.devices.get(name) {
+            Some(Device::Backend(backend)) => Some(backend),
+            _ => None,
+        }
+    }
+
+    /// Turn group names and device names into device keys, other names are keys already.
+    /// Devices of other backends keep their names.
+    pub fn resolve<S: AsRef<str>>(&self, names: impl IntoIterator<Item = S>) -> Vec<String> {
+        let mut keys = Vec::new();
+        for name in names {
+            let name = name.as_ref();
+            let members = match self.groups.get(name) {
+                Some(members) => members.iter().map(String::as_str).collect(),
+                None => vec![name],
+            };
+            for member in members {
+                let key = self.key(member);
+                if !keys.iter().any(|k| k == key) {
+                    keys.push(key.to_owned());
+                }
+            }
+        }
         keys
     }
 }
 
-#[derive(Debug, Clone, Copy, ValueEnum, Serialize)]
+/// Interruption level, sent as the Bark API names it: `critical`, `active`, `timeSensitive`
+/// and `passive`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum Level {
     Critical,
     Active,
@@ -93,7 +264,7 @@ pub enum Level {
     Passive,
 }
 
-#[derive(Serialize, Deserialize, Args, Debug)]
+#[derive(Serialize, Deserialize, Args, Clone, Debug)]
 pub struct Storable {
     /// Set different ringtones
     #[arg(long, short = 'S')]
@@ -114,13 +285,15 @@ pub struct Storable {
     #[arg(skip)]
     #[serde(rename = "isArchive", alias = "archive")]
     #[serde(
+        default,
         skip_serializing_if = "Option::is_none",
-        serialize_with = "ser_option_1"
+        serialize_with = "ser_option_1",
+        deserialize_with = "de_option_flag"
     )]
     is_archive: Option<bool>,
 }
 
-#[derive(Serialize, Args, Debug)]
+#[derive(Serialize, Deserialize, Args, Clone, Debug)]
 pub struct Push {
     /// Push title
     #[arg(long, short = 't')]
@@ -133,8 +306,9 @@ pub struct Push {
     subtitle: Option<String>,
 
     /// Push content
-    #[arg(long, short = 'b')]
-    body: String,
+    #[arg(long, short = 'b', required_unless_present = "from_url")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
 
     /// Push interrupt level
     #[arg(long, short = 'l')]
@@ -143,23 +317,33 @@ pub struct Push {
 
     /// Important warning notification volume
     #[arg(long, short = 'v', value_parser = value_parser!(u32).range(0..=10))]
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "de_option_number"
+    )]
     volume: Option<u32>,
 
     /// Push angle marker, can be any number
     #[arg(long, short = 'B')]
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "de_option_number"
+    )]
     badge: Option<u32>,
 
     /// Repeat notification ringtone
     #[arg(long, short = 'R')]
     #[serde(serialize_with = "ser_true_to_1", skip_serializing_if = "is_false")]
+    #[serde(default, deserialize_with = "de_flag")]
     call: bool,
 
     /// Automatically copy push content
     #[arg(long, short = 'C')]
-    #[serde(rename = "autoCopy")]
+    #[serde(rename = "autoCopy", alias = "auto_copy")]
     #[serde(serialize_with = "ser_true_to_1", skip_serializing_if = "is_false")]
+    #[serde(default, deserialize_with = "de_flag")]
     auto_copy: bool,
 
     /// Specify the copied content. If you do not pass this parameter, the entire push content will be copied.
@@ -172,9 +356,16 @@ pub struct Push {
     #[serde(skip_serializing_if = "Option::is_none")]
     url: Option<String>,
 
+    /// Notification id, a push replaces the notification with the same id
+    // Resolved alerts and escalations rely on it to replace what they sent before
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+
     /// When "none" is transmitted, clicking push will not pop up
     #[arg(long, overrides_with = "no_action")]
     #[serde(skip_serializing_if = "is_false", serialize_with = "ser_action")]
+    #[serde(default, deserialize_with = "de_action")]
     action: bool,
 
     #[command(flatten)]
@@ -200,6 +391,65 @@ impl Storable {
 }
 
 impl Push {
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    pub fn body(&self) -> Option<&str> {
+        self.body.as_deref()
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    pub fn icon(&self) -> Option<&str> {
+        self.store.icon.as_deref()
+    }
+
+    pub fn sound(&self) -> Option<&str> {
+        self.store.sound.as_deref()
+    }
+
+    pub fn call(&self) -> bool {
+        self.call
+    }
+
+    pub fn level(&self) -> Option<Level> {
+        self.level
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.store.group.as_deref()
+    }
+
+    /// The same push without sound, for quiet hours
+    pub fn hushed(&self) -> Self {
+        let mut push = self.clone();
+        push.level = Some(Level::Passive);
+        push.volume = None;
+        push.call = false;
+        push.store.sound = None;
+        push
+    }
+
+    /// A short line to recognize the push by
+    pub fn label(&self) -> String {
+        let label = self.title().or(self.body()).unwrap_or_default();
+        match label.char_indices().nth(40) {
+            Some((i, _)) => format!("{}...", &label[..i]),
+            None => label.to_owned(),
+        }
+    }
+
+    /// The same push with another content
+    pub fn with_body(&self, body: impl Into<String>) -> Self {
+        Self {
+            body: Some(body.into()),
+            ..self.clone()
+        }
+    }
+
     pub fn update_storable(&mut self, other: Storable) {
         self.store.merge(other)
     }
@@ -210,6 +460,24 @@ impl Push {
         }
     }
 
+    pub fn update_call(&mut self, call: bool) {
+        if call {
+            self.call = true;
+        }
+    }
+
+    pub fn update_url(&mut self, url: Option<String>) {
+        if url.is_some() {
+            self.url = url;
+        }
+    }
+
+    pub fn update_id(&mut self, id: Option<String>) {
+        if id.is_some() {
+            self.id = id;
+        }
+    }
+
     pub fn update_archive(&mut self, archive: Option<bool>) {
         if archive.is_some() {
             self.store.is_archive = archive;
@@ -258,6 +526,66 @@ where
     }
 }
 
+/// Values of other tools come as booleans, numbers or strings alike
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Loose {
+    Bool(bool),
+    Number(u64),
+    Text(String),
+}
+
+impl Loose {
+    fn flag(self) -> bool {
+        match self {
+            Loose::Bool(b) => b,
+            Loose::Number(n) => n != 0,
+            Loose::Text(s) => matches!(s.to_lowercase().as_str(), "1" | "true" | "yes"),
+        }
+    }
+}
+
+fn de_flag<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    Ok(Loose::deserialize(deserializer)?.flag())
+}
+
+fn de_option_flag<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    Ok(Option::<Loose>::deserialize(deserializer)?.map(Loose::flag))
+}
+
+fn de_option_number<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    match Option::<Loose>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Loose::Number(n)) => u32::try_from(n).map(Some).map_err(de::Error::custom),
+        Some(Loose::Text(s)) if s.is_empty() => Ok(None),
+        Some(Loose::Text(s)) => s.parse().map(Some).map_err(de::Error::custom),
+        Some(Loose::Bool(b)) => Err(de::Error::invalid_type(
+            de::Unexpected::Bool(b),
+            &"a number",
+        )),
+    }
+}
+
+fn de_action<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    Ok(match Option::<Loose>::deserialize(deserializer)? {
+        Some(Loose::Text(s)) => s == "none",
+        Some(other) => other.flag(),
+        None => false,
+    })
+}
+
 impl<'de> Deserialize<'de> for Level {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
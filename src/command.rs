@@ -1,15 +1,32 @@
 use std::path::{Path, PathBuf};
 
-use clap::{ArgAction, ArgGroup, Parser};
-
-use crate::bark::{Encryption, Level, Push, Service};
+use clap::{ArgAction, ArgGroup, Parser, Subcommand};
+
+use crate::bark::{Encryption, Level, Push, Sections, Service};
+use crate::dedup::Throttle;
+use crate::device::DeviceCommand;
+use crate::escalate::Escalate;
+use crate::heartbeat::HeartbeatCommand;
+use crate::monitor::MonitorArgs;
+use crate::output::OutputFormat;
+use crate::route::Tags;
+use crate::schedule::{ScheduleCommand, When};
+use crate::serve::ServeArgs;
+use crate::server::ServerCommand;
+use crate::smtp::SmtpArgs;
+use crate::spool::Outbox;
+use crate::syslog::SyslogArgs;
+use crate::wait_pid::WaitPidArgs;
+use crate::watch_log::WatchLogArgs;
+use crate::watch_path::WatchPathArgs;
 
 #[derive(Parser, Debug)]
 #[command(
     author = "kc9vu",
     version,
     about = "A cli tool to push notifications to bark servers",
-    max_term_width = 80
+    max_term_width = 80,
+    subcommand_negates_reqs = true
 )]
 #[command(
     group = ArgGroup::new("push_level")
@@ -70,9 +87,110 @@ pub struct Cli {
     #[arg(long, visible_alias = "no-file", short = 'z')]
     pub thats_all: bool,
 
+    /// Read the push, the server and the device key from a Bark url, other options override it
+    #[arg(long, value_name = "URL")]
+    pub from_url: Option<String>,
+
     /// Just print push that will be sent, don't do sending
     #[arg(long, short = 'r')]
     pub dry_run: bool,
+
+    /// Format of dry runs and send results
+    #[arg(long, short = 'o', value_enum, default_value_t = OutputFormat::Human)]
+    pub output: OutputFormat,
+
+    /// Queue pushes that failed to send in the outbox, `barsk flush` sends them later
+    #[arg(long)]
+    pub queue_on_failure: bool,
+
+    #[command(flatten)]
+    pub outbox: Outbox,
+
+    #[command(flatten)]
+    pub throttle: Throttle,
+
+    #[command(flatten)]
+    pub when: When,
+
+    #[command(flatten)]
+    pub escalate: Escalate,
+
+    #[command(flatten)]
+    pub tags: Tags,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+    #[arg(skip)]
+    pub sections: Sections,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Retry pushes queued in the outbox, oldest first
+    Flush,
+
+    /// Deliver scheduled pushes when they are due and escalate pushes, runs until stopped
+    Scheduler,
+
+    /// Manage scheduled pushes
+    Schedule {
+        #[command(subcommand)]
+        command: ScheduleCommand,
+    },
+
+    /// Relay pushes from a Bark compatible HTTP API
+    Serve(ServeArgs),
+
+    /// Turn emails into pushes, the recipient picks the device or group
+    Smtp(SmtpArgs),
+
+    /// Push syslog messages that match the configured rules
+    Syslog(SyslogArgs),
+
+    /// Follow a log file and push lines that match
+    WatchLog(WatchLogArgs),
+
+    /// Push when files under a directory are created, modified or deleted
+    WatchPath(WatchPathArgs),
+
+    /// Wait for running processes to exit and push how long they ran
+    WaitPid(WaitPidArgs),
+
+    /// Rerun a command and push when its output, exit status or value changes
+    Monitor(MonitorArgs),
+
+    /// Record heartbeats and push when one goes silent
+    Heartbeat {
+        #[command(subcommand)]
+        command: HeartbeatCommand,
+    },
+
+    /// Register, list, remove and test devices
+    Device {
+        #[command(subcommand)]
+        command: DeviceCommand,
+    },
+
+    /// Check the health of Bark servers
+    Server {
+        #[command(subcommand)]
+        command: ServerCommand,
+    },
+
+    /// Print the Bark url of the push for each device, for bookmarks, shortcuts or QR codes
+    Url,
+
+    /// Acknowledge escalating pushes, which stops them
+    Ack {
+        /// Ids shown when the escalation started
+        #[arg(required_unless_present = "list")]
+        ids: Vec<String>,
+
+        /// List the pushes that are escalating
+        #[arg(long)]
+        list: bool,
+    },
 }
 
 impl Cli {
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use crate::send::Delivery;
+use crate::state::{fnv1a, lock, read_json, state_dir, write_json};
+use crate::time::{now, parse_duration};
+
+#[derive(Args, Debug)]
+pub struct Throttle {
+    /// Key that identifies duplicate pushes, default is a hash of the push content
+    #[arg(long, value_name = "KEY")]
+    dedup_key: Option<String>,
+
+    /// Skip pushes that duplicate one sent within this time, e.g. 30m
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    suppress_for: Option<Duration>,
+
+    /// Push how many duplicates were suppressed once the window has passed
+    #[arg(long, requires = "suppress_for")]
+    pub summary: bool,
+
+    /// Most pushes a device receives in a period, e.g. 10/1h
+    #[arg(long, env = "BARSK_RATE_LIMIT", value_name = "N/DURATION")]
+    rate_limit: Option<RateLimit>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(try_from = "String")]
+pub struct RateLimit {
+    capacity: u32,
+    period: Duration,
+}
+
+impl RateLimit {
+    /// Tokens of a bucket that had `tokens` some seconds ago
+    fn refill(&self, tokens: f64, elapsed: f64) -> f64 {
+        let capacity = self.capacity as f64;
+        (tokens + elapsed * capacity / self.period.as_secs_f64()).min(capacity)
+    }
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// A token bucket kept in memory, for daemons
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.capacity as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token if there is one
+    pub fn take(&mut self) -> bool {
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        self.tokens = self.limit.refill(self.tokens, elapsed);
+        self.updated = Instant::now();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (capacity, period) = s
+            .split_once('/')
+            .ok_or(anyhow!("Rate limit looks like 10/1h"))?;
+        let capacity = capacity.trim().parse()?;
+        // `10/h` reads as ten per hour
+        let period = match period.trim() {
+            p if p.starts_with(|c: char| c.is_ascii_digit()) => parse_duration(p)?,
+            p => parse_duration(&format!("1{p}"))?,
+        };
+        if capacity == 0 || period.is_zero() {
+            return Err(anyhow!("Rate limit must allow at least one push"));
+        }
+        Ok(Self { capacity, period })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct State {
+    #[serde(default)]
+    windows: HashMap<String, Window>,
+    #[serde(default)]
+    buckets: HashMap<String, Bucket>,
+}
+
+/// A push that was sent, and the duplicates suppressed after it
+#[derive(Serialize, Deserialize, Debug)]
+struct Window {
+    until: u64,
+    suppressed: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: u64,
+}
+
+/// What the throttle decided about one push
+#[derive(Debug, Default)]
+pub struct Verdict {
+    /// The push duplicates a recent one, with how many were suppressed so far
+    pub suppressed: Option<u32>,
+    /// When the window of the duplicated push closes
+    pub window_ends: Option<u64>,
+    /// Key of the window this push opened, closed again if the push fails
+    pub key: Option<String>,
+    /// Devices that may receive the push
+    pub allowed: Vec<String>,
+    /// Devices that ran out of their rate limit
+    pub limited: Vec<String>,
+}
+
+impl Throttle {
+    fn path() -> PathBuf {
+        state_dir().join("throttle.json")
+    }
+
+    fn enabled(&self) -> bool {
+        self.suppress_for.is_some() || self.rate_limit.is_some()
+    }
+
+    /// Decide whether `content` is sent and to which devices, the state is kept unless `dry_run`.
+    /// The window and the tokens are taken now, so concurrent duplicates see them, and given
+    /// back by `record` if the push fails.
+    pub async fn check(
+        &self,
+        content: &str,
+        devices: Vec<String>,
+        dry_run: bool,
+    ) -> Result<Verdict> {
+        if !self.enabled() {
+            return Ok(Verdict {
+                allowed: devices,
+                ..Default::default()
+            });
+        }
+
+        let path = Self::path();
+        let _lock = lock(&path).await?;
+        let mut state = read_json::<State>(&path).await?.unwrap_or_default();
+        let verdict = self.decide(&mut state, content, devices, now());
+
+        if !dry_run {
+            write_json(&path, &state).await?;
+        }
+        Ok(verdict)
+    }
+
+    /// Take the window and the tokens for a push at `now`
+    fn decide(&self, state: &mut State, content: &str, devices: Vec<String>, now: u64) -> Verdict {
+        let mut verdict = Verdict::default();
+
+        if let Some(window) = self.suppress_for {
+            let key = self
+                .dedup_key
+                .clone()
+                .unwrap_or_else(|| format!("{:016x}", fnv1a(content.as_bytes())));
+
+            state.windows.retain(|_, w| w.until > now);
+            match state.windows.get_mut(&key) {
+                Some(open) => {
+                    open.suppressed += 1;
+                    verdict.suppressed = Some(open.suppressed);
+                    verdict.window_ends = Some(open.until);
+                }
+                None => {
+                    state.windows.insert(
+                        key.clone(),
+                        Window {
+                            until: now + window.as_secs(),
+                            suppressed: 0,
+                        },
+                    );
+                }
+            }
+            verdict.key = Some(key);
+        }
+
+        if verdict.suppressed.is_none() {
+            for device in devices {
+                let Some(limit) = self.rate_limit else {
+                    verdict.allowed.push(device);
+                    continue;
+                };
+
+                let bucket = state.buckets.entry(device.clone()).or_insert(Bucket {
+                    tokens: limit.capacity as f64,
+                    updated: now,
+                });
+                bucket.tokens =
+                    limit.refill(bucket.tokens, now.saturating_sub(bucket.updated) as f64);
+                bucket.updated = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    verdict.allowed.push(device);
+                } else {
+                    verdict.limited.push(device);
+                }
+            }
+        }
+        verdict
+    }
+
+    /// Give back what `check` took for a push that failed: the window if no device got it,
+    /// so a retry isn't suppressed, and the tokens of the devices that didn't get it
+    pub async fn record(&self, verdict: &Verdict, results: &[Delivery]) -> Result<()> {
+        let failed = results
+            .iter()
+            .filter(|d| !d.is_success())
+            .collect::<Vec<_>>();
+        if !self.enabled() || verdict.suppressed.is_some() || failed.is_empty() {
+            return Ok(());
+        }
+
+        let path = Self::path();
+        let _lock = lock(&path).await?;
+        let mut state = read_json::<State>(&path).await?.unwrap_or_default();
+        self.give_back(&mut state, verdict, &failed, results.len());
+        write_json(&path, &state).await
+    }
+
+    fn give_back(&self, state: &mut State, verdict: &Verdict, failed: &[&Delivery], sent: usize) {
+        if failed.len() == sent
+            && let Some(key) = &verdict.key
+        {
+            state.windows.remove(key);
+        }
+        if let Some(limit) = self.rate_limit {
+            for delivery in failed {
+                if let Some(bucket) = state.buckets.get_mut(&delivery.device) {
+                    bucket.tokens = (bucket.tokens + 1.0).min(limit.capacity as f64);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(suppress_for: Option<u64>, rate_limit: Option<&str>) -> Throttle {
+        Throttle {
+            dedup_key: None,
+            suppress_for: suppress_for.map(Duration::from_secs),
+            summary: false,
+            rate_limit: rate_limit.map(|r| r.parse().unwrap()),
+        }
+    }
+
+    fn devices(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn failed(device: &str) -> Delivery {
+        let mut delivery = Delivery::new(device.to_owned());
+        delivery.error = Some("unreachable".to_owned());
+        delivery
+    }
+
+    #[test]
+    fn parse_rate_limit() {
+        let limit = "10/1h".parse::<RateLimit>().unwrap();
+        assert_eq!((limit.capacity, limit.period.as_secs()), (10, 3600));
+        let limit = "5/m".parse::<RateLimit>().unwrap();
+        assert_eq!((limit.capacity, limit.period.as_secs()), (5, 60));
+        assert!("0/1h".parse::<RateLimit>().is_err());
+        assert!("10".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn refill() {
+        let limit = "10/100s".parse::<RateLimit>().unwrap();
+        assert_eq!(limit.refill(0.0, 10.0), 1.0);
+        assert_eq!(limit.refill(2.5, 5.0), 3.0);
+        assert_eq!(limit.refill(9.0, 1000.0), 10.0);
+    }
+
+    #[test]
+    fn duplicates_in_the_window() {
+        let throttle = throttle(Some(60), None);
+        let mut state = State::default();
+
+        let first = throttle.decide(&mut state, "disk full", devices(&["a"]), 1000);
+        assert_eq!(first.suppressed, None);
+        assert_eq!(first.allowed, devices(&["a"]));
+
+        let second = throttle.decide(&mut state, "disk full", devices(&["a"]), 1030);
+        assert_eq!(second.suppressed, Some(1));
+        assert_eq!(second.window_ends, Some(1060));
+        assert!(second.allowed.is_empty());
+        let third = throttle.decide(&mut state, "disk full", devices(&["a"]), 1059);
+        assert_eq!(third.suppressed, Some(2));
+
+        let other = throttle.decide(&mut state, "disk ok", devices(&["a"]), 1030);
+        assert_eq!(other.suppressed, None);
+
+        let expired = throttle.decide(&mut state, "disk full", devices(&["a"]), 1060);
+        assert_eq!(expired.suppressed, None);
+        assert_eq!(expired.allowed, devices(&["a"]));
+    }
+
+    #[test]
+    fn dedup_key() {
+        let mut throttle = throttle(Some(60), None);
+        throttle.dedup_key = Some("disk".to_owned());
+        let mut state = State::default();
+
+        throttle.decide(&mut state, "disk 91%", devices(&["a"]), 1000);
+        let verdict = throttle.decide(&mut state, "disk 92%", devices(&["a"]), 1001);
+        assert_eq!(verdict.suppressed, Some(1));
+    }
+
+    #[test]
+    fn rate_limit_per_device() {
+        let throttle = throttle(None, Some("2/60s"));
+        let mut state = State::default();
+
+        for now in [1000, 1001] {
+            let verdict = throttle.decide(&mut state, "x", devices(&["a"]), now);
+            assert_eq!(verdict.allowed, devices(&["a"]));
+        }
+        let verdict = throttle.decide(&mut state, "x", devices(&["a", "b"]), 1002);
+        assert_eq!(verdict.allowed, devices(&["b"]));
+        assert_eq!(verdict.limited, devices(&["a"]));
+
+        // One token comes back every 30 seconds
+        let verdict = throttle.decide(&mut state, "x", devices(&["a"]), 1031);
+        assert_eq!(verdict.allowed, devices(&["a"]));
+        let verdict = throttle.decide(&mut state, "x", devices(&["a"]), 1032);
+        assert_eq!(verdict.limited, devices(&["a"]));
+    }
+
+    #[test]
+    fn give_back_on_failure() {
+        let throttle = throttle(Some(60), Some("1/1h"));
+        let mut state = State::default();
+
+        let verdict = throttle.decide(&mut state, "x", devices(&["a", "b"]), 1000);
+        let (a, b) = (failed("a"), Delivery::new("b".to_owned()));
+        throttle.give_back(&mut state, &verdict, &[&a], 2);
+
+        // The window stays open since b got the push, a has its token back
+        assert!(state.windows.contains_key(verdict.key.as_ref().unwrap()));
+        assert_eq!(state.buckets["a"].tokens, 1.0);
+        assert_eq!(state.buckets["b"].tokens, 0.0);
+
+        throttle.give_back(&mut state, &verdict, &[&a, &b], 2);
+        assert!(state.windows.is_empty());
+        assert_eq!(state.buckets["a"].tokens, 1.0);
+    }
+}
@@ -0,0 +1,182 @@
+use std::path::Path;
+
+use anstream::println;
+use anyhow::{Result, anyhow, bail};
+use clap::Subcommand;
+use owo_colors::OwoColorize;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use tokio::fs;
+
+use crate::bark::{Device, Encryption, Push, Service};
+use crate::edit;
+use crate::send::{self, Pusher};
+use crate::state;
+use crate::time::{format_local, now};
+
+#[derive(Subcommand, Debug)]
+pub enum DeviceCommand {
+    /// Register a device token with the server and save the key under a name in the configuration
+    Register {
+        /// Name of the device in the configuration
+        name: String,
+
+        /// APNs device token of the phone, shown in the Bark app
+        #[arg(long)]
+        token: String,
+
+        /// Key to register under, the server makes one if missing
+        #[arg(long)]
+        key: Option<String>,
+    },
+
+    /// List the devices and groups of the configuration
+    List,
+
+    /// Remove a device from the configuration
+    Remove {
+        /// Name of the device
+        name: String,
+    },
+
+    /// Send a verification push to a device or group
+    Test {
+        /// Name of the device or group, or a device key
+        name: String,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct Registered {
+    code: u16,
+    message: String,
+    #[serde(default)]
+    data: Option<Map<String, Value>>,
+}
+
+/// Register `token` on the server, return the device key
+async fn register(service: &Service, token: &str, key: Option<&str>) -> Result<String> {
+    let url = send::endpoint(service.server(), "register")?;
+    let resp = send::client()?
+        .post(url)
+        .json(&json!({ "devicetoken": token, "key": key }))
+        .send()
+        .await?
+        .json::<Registered>()
+        .await?;
+    if resp.code != 200 {
+        bail!("Registration failed: {}", resp.message);
+    }
+    resp.data
+        .as_ref()
+        .and_then(|data| data.get("key").or(data.get("device_key")))
+        .and_then(Value::as_str)
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow!("The server returned no device key"))
+}
+
+/// Replace the configuration file through a temporary file with its permissions, so that
+/// nothing ever sees half of it
+async fn write(path: &Path, text: String) -> Result<()> {
+    let path = match fs::canonicalize(path).await {
+        Ok(path) => path,
+        Err(er) if er.kind() == std::io::ErrorKind::NotFound => path.to_path_buf(),
+        Err(er) => return Err(er.into()),
+    };
+    let tmp = state::tmp_path(&path);
+    fs::write(&tmp, text).await?;
+    if let Ok(meta) = fs::metadata(&path).await {
+        fs::set_permissions(&tmp, meta.permissions()).await?;
+    }
+    if let Err(er) = fs::rename(&tmp, &path).await {
+        fs::remove_file(&tmp).await.ok();
+        return Err(er.into());
+    }
+    Ok(())
+}
+
+async fn read(path: &Path) -> Result<String> {
+    match fs::read_to_string(path).await {
+        Ok(text) => Ok(text),
+        Err(er) if er.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(er) => Err(er.into()),
+    }
+}
+
+pub async fn manage(
+    command: &DeviceCommand,
+    config: Option<&Path>,
+    service: Service,
+    encryption: Encryption,
+) -> Result<()> {
+    let config = || config.ok_or_else(|| anyhow!("No configuration file, give one with -F"));
+    match command {
+        DeviceCommand::Register { name, token, key } => {
+            let path = config()?;
+            let key = register(&service, token, key.as_deref()).await?;
+            let mut text = read(path).await?;
+            if text.trim().is_empty() && !path.to_string_lossy().ends_with(".toml") {
+                text = "{\n}\n".to_owned();
+            }
+            let text = edit::set_device(&path.to_string_lossy(), &text, name, &key)?;
+            write(path, text).await?;
+            println!(
+                "{}: {} as {} in {}",
+                "registered".green(),
+                name.cyan(),
+                crate::hide_str(&key),
+                path.display()
+            );
+            Ok(())
+        }
+        DeviceCommand::List => {
+            let mut devices = service.devices().iter().collect::<Vec<_>>();
+            devices.sort_by_key(|(name, _)| *name);
+            for (name, device) in devices {
+                let target = match device {
+                    Device::Key(key) => crate::hide_str(key),
+                    Device::Backend(backend) => backend.kind().to_owned(),
+                };
+                println!("{}  {}", name.cyan(), target);
+            }
+            let mut groups = service.groups().iter().collect::<Vec<_>>();
+            groups.sort_by_key(|(name, _)| *name);
+            for (name, members) in groups {
+                println!("{}  {}", name.blue(), members.join(", "));
+            }
+            Ok(())
+        }
+        DeviceCommand::Remove { name } => {
+            let path = config()?;
+            let text = read(path).await?;
+            let Some(text) = edit::remove_device(&path.to_string_lossy(), &text, name)? else {
+                bail!("No device {name} in {}", path.display());
+            };
+            write(path, text).await?;
+            println!("{}: {}", "removed".green(), name);
+            let groups = service
+                .groups()
+                .iter()
+                .filter(|(_, members)| members.contains(name))
+                .map(|(group, _)| group.as_str())
+                .collect::<Vec<_>>();
+            if !groups.is_empty() {
+                println!("{}: {}", "still in groups".yellow(), groups.join(", "));
+            }
+            Ok(())
+        }
+        DeviceCommand::Test { name } => {
+            let mut fields = Map::new();
+            fields.insert("title".to_owned(), "barsk".into());
+            fields.insert(
+                "body".to_owned(),
+                format!("Test push to {name} at {}", format_local(now())).into(),
+            );
+            let push = serde_json::from_value::<Push>(Value::Object(fields))?;
+            // Quiet hours could hush or defer it, routes could send it elsewhere
+            Pusher::new(service, encryption)?
+                .push_now(&push, std::slice::from_ref(name))
+                .await
+        }
+    }
+}
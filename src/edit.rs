@@ -0,0 +1,488 @@
+use anyhow::{Context, Result, anyhow};
+use toml_edit::{DocumentMut, Item, Value, table};
+
+/// A token of JSON5 text, strings are kept without their quotes
+#[derive(Debug, PartialEq)]
+enum Kind {
+    Punct(u8),
+    Word(String),
+}
+
+#[derive(Debug)]
+struct Token {
+    kind: Kind,
+    start: usize,
+    end: usize,
+}
+
+/// A member of an object, by the indices of its tokens
+struct Entry {
+    key: String,
+    name: usize,
+    value: usize,
+    last: usize,
+    comma: Option<usize>,
+}
+
+/// The tokens of JSON5 text, without whitespace and comments
+fn tokenize(text: &str) -> Option<Vec<Token>> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    // Bytes, not text: an unquoted word may hold characters of more than one byte
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        if bytes[i].is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        if rest.starts_with(b"//") {
+            i += rest.iter().position(|b| *b == b'\n').unwrap_or(rest.len());
+            continue;
+        }
+        if rest.starts_with(b"/*") {
+            i += rest[2..].windows(2).position(|end| end == b"*/")? + 4;
+            continue;
+        }
+
+        let start = i;
+        let kind = match bytes[i] {
+            b @ (b'{' | b'}' | b'[' | b']' | b':' | b',') => {
+                i += 1;
+                Kind::Punct(b)
+            }
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                if i >= bytes.len() {
+                    return None;
+                }
+                i += 1;
+                Kind::Word(text[start + 1..i - 1].to_owned())
+            }
+            _ => {
+                while i < bytes.len()
+                    && !bytes[i].is_ascii_whitespace()
+                    && !b"{}[]:,\"'".contains(&bytes[i])
+                    && !bytes[i..].starts_with(b"//")
+                    && !bytes[i..].starts_with(b"/*")
+                {
+                    i += 1;
+                }
+                Kind::Word(text[start..i].to_owned())
+            }
+        };
+        tokens.push(Token {
+            kind,
+            start,
+            end: i,
+        });
+    }
+    Some(tokens)
+}
+
+/// The index of the last token of the value that starts at `i`
+fn skip(tokens: &[Token], i: usize) -> Option<usize> {
+    match tokens.get(i)?.kind {
+        Kind::Punct(b'{' | b'[') => {
+            let mut depth = 0;
+            for (j, token) in tokens.iter().enumerate().skip(i) {
+                match token.kind {
+                    Kind::Punct(b'{' | b'[') => depth += 1,
+                    Kind::Punct(b'}' | b']') => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(j);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            None
+        }
+        Kind::Punct(_) => None,
+        Kind::Word(_) => Some(i),
+    }
+}
+
+/// The members of the object that opens at token `open`, and the token that closes it
+fn entries(tokens: &[Token], open: usize) -> Option<(Vec<Entry>, usize)> {
+    let mut entries = Vec::new();
+    let mut i = open + 1;
+    loop {
+        match &tokens.get(i)?.kind {
+            Kind::Punct(b'}') => return Some((entries, i)),
+            Kind::Word(key) if tokens.get(i + 1)?.kind == Kind::Punct(b':') => {
+                let last = skip(tokens, i + 2)?;
+                let comma = tokens
+                    .get(last + 1)
+                    .filter(|token| token.kind == Kind::Punct(b','))
+                    .map(|_| last + 1);
+                entries.push(Entry {
+                    key: key.clone(),
+                    name: i,
+                    value: i + 2,
+                    last,
+                    comma,
+                });
+                i = comma.unwrap_or(last) + 1;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Leading whitespace of the line that holds `pos`
+fn indent(text: &str, pos: usize) -> &str {
+    let start = text[..pos].rfind('\n').map_or(0, |n| n + 1);
+    let line = &text[start..pos];
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Add a member to an object, after its last member in the same layout.
+/// `member` gets the indentation of the members and the unit of indentation.
+fn insert(
+    text: &str,
+    tokens: &[Token],
+    entries: &[Entry],
+    open: usize,
+    close: usize,
+    member: impl Fn(&str, &str) -> String,
+) -> String {
+    let outer = indent(text, tokens[open].start);
+    let Some(last) = entries.last() else {
+        // An empty object inside an object on one line stays on that line
+        let line_start = text[..tokens[open].start].rfind('\n').map_or(0, |n| n + 1);
+        if text[line_start..tokens[open].start].contains('{') {
+            return format!(
+                "{}{{ {} }}{}",
+                &text[..tokens[open].start],
+                member("", ""),
+                &text[tokens[close].end..]
+            );
+        }
+        // Members of the top object tell the unit, the devices object is one level down
+        let unit = if outer.is_empty() { "    " } else { outer };
+        let inner = format!("{outer}{unit}");
+        return format!(
+            "{}{{\n{inner}{}\n{outer}}}{}",
+            &text[..tokens[open].start],
+            member(&inner, unit),
+            &text[tokens[close].end..]
+        );
+    };
+
+    let value_end = tokens[last.last].end;
+    let after = tokens[last.comma.unwrap_or(last.last)].end;
+    let line_end = text[after..].find('\n').map_or(text.len(), |n| after + n);
+    let comma = if last.comma.is_some() { "" } else { "," };
+    if tokens[close].start < line_end {
+        // An object on one line
+        let trailing = if last.comma.is_some() { "," } else { "" };
+        return format!(
+            "{}{comma} {}{trailing}{}",
+            &text[..after],
+            member("", ""),
+            &text[after..]
+        );
+    }
+    let indent = indent(text, tokens[last.name].start);
+    let unit = indent.strip_prefix(outer).unwrap_or("    ");
+    let trailing = if last.comma.is_some() { "," } else { "" };
+    format!(
+        "{}{comma}{}\n{indent}{}{trailing}{}",
+        &text[..value_end],
+        &text[value_end..line_end],
+        member(indent, unit),
+        &text[line_end..]
+    )
+}
+
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_default()
+}
+
+/// Set `devices.<name>` of a JSON5 configuration to a device key
+fn set_json5(text: &str, name: &str, key: &str) -> Option<String> {
+    let tokens = tokenize(text)?;
+    if tokens.first()?.kind != Kind::Punct(b'{') {
+        return None;
+    }
+    let (top, close) = entries(&tokens, 0)?;
+    let Some(devices) = top.iter().find(|entry| entry.key == "devices") else {
+        return Some(insert(text, &tokens, &top, 0, close, |indent, unit| {
+            format!(
+                "\"devices\": {{\n{indent}{unit}{}: {}\n{indent}}}",
+                quote(name),
+                quote(key)
+            )
+        }));
+    };
+
+    if tokens[devices.value].kind != Kind::Punct(b'{') {
+        return None;
+    }
+    let (members, close) = entries(&tokens, devices.value)?;
+    match members.iter().find(|entry| entry.key == name) {
+        Some(entry) => Some(format!(
+            "{}{}{}",
+            &text[..tokens[entry.value].start],
+            quote(key),
+            &text[tokens[entry.last].end..]
+        )),
+        None => Some(insert(
+            text,
+            &tokens,
+            &members,
+            devices.value,
+            close,
+            |_, _| format!("{}: {}", quote(name), quote(key)),
+        )),
+    }
+}
+
+/// Remove `devices.<name>` of a JSON5 configuration, and its line if nothing else is on it
+fn remove_json5(text: &str, name: &str) -> Option<Option<String>> {
+    let tokens = tokenize(text)?;
+    if tokens.first()?.kind != Kind::Punct(b'{') {
+        return None;
+    }
+    let (top, _) = entries(&tokens, 0)?;
+    let Some(devices) = top.iter().find(|entry| entry.key == "devices") else {
+        return Some(None);
+    };
+    if tokens[devices.value].kind != Kind::Punct(b'{') {
+        return None;
+    }
+    let (members, _) = entries(&tokens, devices.value)?;
+    let Some(entry) = members.iter().find(|entry| entry.key == name) else {
+        return Some(None);
+    };
+
+    let mut start = tokens[entry.name].start;
+    let mut end = tokens[entry.comma.unwrap_or(entry.last)].end;
+    let line_start = text[..start].rfind('\n').map_or(0, |n| n + 1);
+    let line_end = text[end..].find('\n').map_or(text.len(), |n| end + n);
+    let rest = text[end..line_end].trim();
+    if text[line_start..start].trim().is_empty()
+        && (rest.is_empty()
+            || rest.starts_with("//")
+            || rest.starts_with("/*") && rest.ends_with("*/"))
+    {
+        start = line_start;
+        end = (line_end + 1).min(text.len());
+    } else {
+        end += text[end..].len() - text[end..].trim_start_matches(' ').len();
+    }
+    let mut text = format!("{}{}", &text[..start], &text[end..]);
+
+    // The member before the last one loses its comma too
+    let index = members
+        .iter()
+        .position(|member| member.name == entry.name)?;
+    if entry.comma.is_none()
+        && let Some(previous) = index.checked_sub(1).map(|i| &members[i])
+        && let Some(comma) = previous.comma
+    {
+        // Spaces after the comma go too if nothing follows them on the line
+        let after = &text[tokens[comma].end..];
+        let spaces = after.len() - after.trim_start_matches(' ').len();
+        let spaces = if matches!(after[spaces..].chars().next(), None | Some('\n' | '\r')) {
+            spaces
+        } else {
+            0
+        };
+        text.replace_range(tokens[comma].start..tokens[comma].end + spaces, "");
+    }
+    Some(Some(text))
+}
+
+fn is_toml(path: &str) -> bool {
+    path.ends_with(".toml")
+}
+
+/// Set a device key of the `devices` section, keeping the rest of the file as it is
+pub fn set_device(path: &str, text: &str, name: &str, key: &str) -> Result<String> {
+    if !is_toml(path) {
+        return set_json5(text, name, key)
+            .ok_or_else(|| anyhow!("Can't find where to put the device in {path}"));
+    }
+
+    let mut doc = text.parse::<DocumentMut>()?;
+    let devices = doc
+        .entry("devices")
+        .or_insert(table())
+        .as_table_like_mut()
+        .context("devices is not a table")?;
+    match devices.get_mut(name) {
+        // Keep the decoration of the old key
+        Some(Item::Value(Value::String(old))) => {
+            let decor = old.decor().clone();
+            let mut new = toml_edit::Formatted::new(key.to_owned());
+            *new.decor_mut() = decor;
+            *old = new;
+        }
+        _ => {
+            devices.insert(name, toml_edit::value(key));
+        }
+    }
+    Ok(doc.to_string())
+}
+
+/// Remove a device of the `devices` section, `None` if it isn't there
+pub fn remove_device(path: &str, text: &str, name: &str) -> Result<Option<String>> {
+    if !is_toml(path) {
+        return remove_json5(text, name).ok_or_else(|| anyhow!("Can't read the devices of {path}"));
+    }
+
+    let mut doc = text.parse::<DocumentMut>()?;
+    let removed = doc
+        .get_mut("devices")
+        .and_then(Item::as_table_like_mut)
+        .and_then(|devices| devices.remove(name));
+    Ok(removed.map(|_| doc.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Set a device and check that the result is JSON5 with the device
+    fn set(text: &str, name: &str, key: &str) -> String {
+        let text = set_json5(text, name, key).unwrap();
+        let config = json5::from_str::<serde_json::Value>(&text).unwrap();
+        assert_eq!(config["devices"][name], key, "{text}");
+        text
+    }
+
+    /// Remove a device and check that the result is JSON5 without the device
+    fn remove(text: &str, name: &str) -> String {
+        let text = remove_json5(text, name).unwrap().unwrap();
+        let config = json5::from_str::<serde_json::Value>(&text).unwrap();
+        assert!(config["devices"].get(name).is_none(), "{text}");
+        text
+    }
+
+    #[test]
+    fn inline() {
+        let text = r#"{ server: "https://x", devices: { phone: "a" } }"#;
+        assert_eq!(
+            set(text, "tablet", "b"),
+            r#"{ server: "https://x", devices: { phone: "a", "tablet": "b" } }"#
+        );
+        assert_eq!(
+            set(text, "phone", "c"),
+            r#"{ server: "https://x", devices: { phone: "c" } }"#
+        );
+        assert_eq!(
+            remove(text, "phone"),
+            r#"{ server: "https://x", devices: { } }"#
+        );
+    }
+
+    #[test]
+    fn multiline() {
+        let text = "{\n  devices: {\n    phone: \"a\",\n    tablet: \"b\"\n  }\n}\n";
+        assert_eq!(
+            set(text, "watch", "c"),
+            "{\n  devices: {\n    phone: \"a\",\n    tablet: \"b\",\n    \"watch\": \"c\"\n  }\n}\n"
+        );
+        assert_eq!(
+            remove(text, "tablet"),
+            "{\n  devices: {\n    phone: \"a\"\n  }\n}\n"
+        );
+        assert_eq!(
+            remove(text, "phone"),
+            "{\n  devices: {\n    tablet: \"b\"\n  }\n}\n"
+        );
+    }
+
+    #[test]
+    fn commented() {
+        let text =
+            "// devices: { x }\n{\n  /* } */ devices: { // {\n    phone: 'a', // old\n  },\n}\n";
+        assert_eq!(
+            set(text, "phone", "b"),
+            "// devices: { x }\n{\n  /* } */ devices: { // {\n    phone: \"b\", // old\n  },\n}\n"
+        );
+        assert_eq!(
+            set(text, "tablet", "c"),
+            "// devices: { x }\n{\n  /* } */ devices: { // {\n    phone: 'a', // old\n    \"tablet\": \"c\",\n  },\n}\n"
+        );
+        assert_eq!(
+            remove(text, "phone"),
+            "// devices: { x }\n{\n  /* } */ devices: { // {\n  },\n}\n"
+        );
+        assert_eq!(set_json5("{ /* open", "phone", "a"), None);
+        assert_eq!(set_json5("{ /*/ devices: {} }", "phone", "a"), None);
+    }
+
+    #[test]
+    fn trailing_commas() {
+        let text = "{\n  server: \"https://x\",\n  devices: {\n    phone: \"a\",\n  },\n}\n";
+        assert_eq!(
+            set(text, "tablet", "b"),
+            "{\n  server: \"https://x\",\n  devices: {\n    phone: \"a\",\n    \"tablet\": \"b\",\n  },\n}\n"
+        );
+        assert_eq!(
+            remove(text, "phone"),
+            "{\n  server: \"https://x\",\n  devices: {\n  },\n}\n"
+        );
+        assert_eq!(
+            set(r#"{ devices: { phone: "a", }, }"#, "tablet", "b"),
+            r#"{ devices: { phone: "a", "tablet": "b", }, }"#
+        );
+    }
+
+    #[test]
+    fn empty_objects() {
+        assert_eq!(
+            set("{}", "phone", "a"),
+            "{\n    \"devices\": {\n        \"phone\": \"a\"\n    }\n}"
+        );
+        assert_eq!(
+            set("{ devices: {} }", "phone", "a"),
+            r#"{ devices: { "phone": "a" } }"#
+        );
+        assert_eq!(
+            set("{\n  devices: {},\n}\n", "phone", "a"),
+            "{\n  devices: {\n    \"phone\": \"a\"\n  },\n}\n"
+        );
+        assert_eq!(remove_json5("{}", "phone"), Some(None));
+        assert_eq!(remove_json5("{ devices: {} }", "phone"), Some(None));
+    }
+
+    #[test]
+    fn unquoted_keys_beyond_ascii() {
+        let text = r#"{ devices: { télé: "a", 電話: "b" } }"#;
+        assert_eq!(
+            set(text, "tablet", "c"),
+            r#"{ devices: { télé: "a", 電話: "b", "tablet": "c" } }"#
+        );
+        assert_eq!(remove(text, "télé"), r#"{ devices: { 電話: "b" } }"#);
+        assert_eq!(remove(text, "電話"), r#"{ devices: { télé: "a" } }"#);
+    }
+
+    #[test]
+    fn members_on_one_line() {
+        let text = "{\n  devices: {\n    phone: \"a\", tablet: \"b\"\n  }\n}\n";
+        assert_eq!(
+            remove(text, "tablet"),
+            "{\n  devices: {\n    phone: \"a\"\n  }\n}\n"
+        );
+        assert_eq!(
+            remove(text, "phone"),
+            "{\n  devices: {\n    tablet: \"b\"\n  }\n}\n"
+        );
+    }
+
+    #[test]
+    fn not_an_object() {
+        assert_eq!(set_json5("[1, 2]", "phone", "a"), None);
+        assert_eq!(remove_json5("[1, 2]", "phone"), None);
+        assert_eq!(remove_json5(r#"{ devices: ["phone"] }"#, "phone"), None);
+        assert_eq!(remove_json5("phone: 1", "phone"), None);
+    }
+}
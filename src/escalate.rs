@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anstream::{eprintln, println};
+use anyhow::{Result, anyhow, bail};
+use clap::Args;
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::bark::{Level, Push};
+use crate::output::print_results;
+use crate::send::{Delivery, Pusher, check};
+use crate::serve::same_token;
+use crate::state::{fnv1a, read_json, state_dir, write_json};
+use crate::time::{de_duration, de_option_duration, format_local, now};
+
+const TICK: Duration = Duration::from_secs(10);
+
+#[derive(Args, Debug)]
+pub struct Escalate {
+    /// Escalate the push by a policy of the configuration until it is acknowledged
+    #[arg(long, value_name = "POLICY")]
+    pub escalate: Option<String>,
+}
+
+/// A policy of the `escalations` section
+#[derive(Deserialize, Debug)]
+pub struct Policy {
+    /// Pushes in order, each after its delay since the first one
+    steps: Vec<Step>,
+
+    /// Send the last step again this often until acknowledged
+    #[serde(default, deserialize_with = "de_option_duration")]
+    repeat: Option<Duration>,
+
+    /// Base url of `barsk serve`, the push links to `<ack_url>/ack/<id>/<secret>`
+    #[serde(default)]
+    ack_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Step {
+    /// Delay since the first push, e.g. 10m
+    #[serde(default, deserialize_with = "de_duration")]
+    after: Duration,
+
+    #[serde(default)]
+    level: Option<Level>,
+
+    #[serde(default)]
+    call: bool,
+
+    /// Devices or groups of the step, the default devices if empty
+    #[serde(default)]
+    devices: Vec<String>,
+}
+
+/// An escalating push, one json file until it is acknowledged or done
+#[derive(Serialize, Deserialize, Debug)]
+struct Item {
+    id: String,
+    policy: String,
+    push: Push,
+    started: u64,
+    /// Index of the next step
+    step: usize,
+    /// When the next step or repeat is due
+    due: u64,
+    /// Random part of the ack url, so that only who got the push can acknowledge it there
+    #[serde(default)]
+    secret: String,
+}
+
+/// An escalation that was started, for the report of the push
+#[derive(Serialize, Debug)]
+pub struct Started {
+    pub id: String,
+    pub policy: String,
+}
+
+fn dir() -> PathBuf {
+    state_dir().join("escalation")
+}
+
+fn item_path(id: &str) -> PathBuf {
+    dir().join(format!("{id}.json"))
+}
+
+fn ack_path(id: &str) -> PathBuf {
+    dir().join(format!("{id}.ack"))
+}
+
+async fn exists(path: &Path) -> bool {
+    fs::try_exists(path).await.unwrap_or(false)
+}
+
+fn secret() -> Result<String> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).map_err(|er| anyhow!("No random secret: {er}"))?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+impl Policy {
+    /// When step `step` is due, for an item started at `started`
+    fn due(&self, started: u64, step: usize, last_sent: u64) -> Option<u64> {
+        match self.steps.get(step) {
+            Some(next) => Some(started + next.after.as_secs()),
+            None => self.repeat.map(|repeat| last_sent + repeat.as_secs()),
+        }
+    }
+}
+
+/// Send one step of an item
+async fn send(pusher: &Pusher, policy: &Policy, item: &Item, step: &Step) -> Result<Vec<Delivery>> {
+    let mut push = item.push.clone();
+    push.update_level(step.level);
+    push.update_call(step.call);
+    // Later steps replace the notification of earlier ones
+    push.update_id(Some(item.id.clone()));
+    if let Some(ack_url) = &policy.ack_url {
+        push.update_url(Some(format!(
+            "{}/ack/{}/{}",
+            ack_url.trim_end_matches('/'),
+            item.id,
+            item.secret
+        )));
+    }
+    let body = format!(
+        "{}\nacknowledge: barsk ack {}",
+        push.body().unwrap_or_default(),
+        item.id
+    );
+    pusher.deliver(&push.with_body(body), &step.devices).await
+}
+
+/// Start escalating `push` by the named policy now or at `at`, the first step is sent if it
+/// is due now and its results returned
+pub async fn start(
+    name: &str,
+    push: &Push,
+    policies: &HashMap<String, Policy>,
+    pusher: &Pusher,
+    at: Option<u64>,
+) -> Result<(Started, Vec<Delivery>)> {
+    let policy = policies
+        .get(name)
+        .ok_or_else(|| anyhow!("No escalation policy {name:?} in the configuration file"))?;
+    if policy.steps.is_empty() {
+        bail!("Escalation policy {name:?} has no steps");
+    }
+
+    let started = at.unwrap_or_default().max(now());
+    let seed = format!("{started}-{}-{}", std::process::id(), push.label());
+    let mut item = Item {
+        id: format!("{:016x}", fnv1a(seed.as_bytes())),
+        policy: name.to_owned(),
+        push: push.clone(),
+        started,
+        step: 0,
+        due: started + policy.steps[0].after.as_secs(),
+        secret: secret()?,
+    };
+    let mut results = Vec::new();
+    if item.due <= now() {
+        results = send(pusher, policy, &item, &policy.steps[0]).await?;
+        check(&results)?;
+        item.step = 1;
+        item.due = policy.due(started, 1, started).unwrap_or(u64::MAX);
+    }
+    if item.due != u64::MAX {
+        write_json(&item_path(&item.id), &item).await?;
+    }
+    let started = Started {
+        id: item.id,
+        policy: name.to_owned(),
+    };
+    Ok((started, results))
+}
+
+/// Stop escalating, the daemon drops the item at its next check
+pub async fn ack(id: &str) -> Result<()> {
+    acknowledge(id, None).await
+}
+
+/// Stop escalating from the ack url, which has to carry the secret of the item
+pub async fn ack_url(id: &str, secret: &str) -> Result<()> {
+    acknowledge(id, Some(secret)).await
+}
+
+async fn acknowledge(id: &str, secret: Option<&str>) -> Result<()> {
+    let item = match !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => read_json::<Item>(&item_path(id)).await?,
+        false => None,
+    };
+    let known = item.is_some_and(|item| {
+        secret.is_none_or(|secret| !item.secret.is_empty() && same_token(&item.secret, secret))
+    });
+    if !known {
+        bail!("No escalation {id}");
+    }
+    fs::write(ack_path(id), now().to_string()).await?;
+    Ok(())
+}
+
+/// Send the steps that are due, once, an item that fails is tried again on the next tick
+async fn tick(pusher: &Pusher, policies: &HashMap<String, Policy>) -> Result<()> {
+    let mut entries = match fs::read_dir(dir()).await {
+        Ok(entries) => entries,
+        Err(er) if er.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(er) => return Err(er.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        if let Err(er) = escalate(pusher, policies, &path).await {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            eprintln!("{} {}: {}", "escalation failed".red(), name, er);
+        }
+    }
+    Ok(())
+}
+
+/// Send the step of the item at `path` if it is due
+async fn escalate(pusher: &Pusher, policies: &HashMap<String, Policy>, path: &Path) -> Result<()> {
+    let Some(mut item) = read_json::<Item>(path).await? else {
+        return Ok(());
+    };
+
+    if exists(&ack_path(&item.id)).await {
+        fs::remove_file(path).await?;
+        fs::remove_file(ack_path(&item.id)).await?;
+        println!("{}: {}", "acknowledged".green(), item.id.cyan());
+        return Ok(());
+    }
+    let now = now();
+    if item.due > now {
+        return Ok(());
+    }
+    // The configuration may have changed since the item was started
+    let policy = policies.get(&item.policy);
+    let Some(policy) = policy.filter(|policy| !policy.steps.is_empty()) else {
+        let why = match policy {
+            Some(_) => "has no steps",
+            None => "is not in the configuration",
+        };
+        eprintln!(
+            "{}: {} by policy {} that {why}",
+            "escalation dropped".red(),
+            item.id,
+            item.policy
+        );
+        fs::remove_file(path).await?;
+        return Ok(());
+    };
+
+    let step = &policy.steps[item.step.min(policy.steps.len() - 1)];
+    let results = send(pusher, policy, &item, step).await?;
+    print_results(&results);
+    check(&results)?;
+    println!("{}: {}", "escalated".yellow(), item.id.cyan());
+    item.step = (item.step + 1).min(policy.steps.len());
+    match policy.due(item.started, item.step, now) {
+        Some(due) => {
+            item.due = due;
+            write_json(path, &item).await?;
+        }
+        None => {
+            println!("{}: {} sent every step", "escalation done".cyan(), item.id);
+            fs::remove_file(path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Escalate pushes until stopped
+pub async fn run(pusher: &Pusher, policies: &HashMap<String, Policy>) -> Result<()> {
+    println!("Escalations watching {}", dir().display());
+    loop {
+        if let Err(er) = tick(pusher, policies).await {
+            eprintln!("{}: {}", "escalations".red(), er);
+        }
+        tokio::time::sleep(TICK).await;
+    }
+}
+
+/// Print the pushes that are escalating
+pub async fn list() -> Result<()> {
+    let mut entries = match fs::read_dir(dir()).await {
+        Ok(entries) => entries,
+        Err(er) if er.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(er) => return Err(er.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "json")
+            && let Some(item) = read_json::<Item>(&path).await?
+        {
+            println!(
+                "{}  {}  next at {}  {}",
+                item.id.cyan(),
+                item.policy,
+                format_local(item.due),
+                item.push.label()
+            );
+        }
+    }
+    Ok(())
+}
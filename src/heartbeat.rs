@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anstream::{eprintln, println};
+use anyhow::{Result, bail};
+use clap::Subcommand;
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::bark::{Encryption, Push, Service};
+use crate::send::Pusher;
+use crate::state::{fnv1a, read_json, state_dir, write_json};
+use crate::time::{de_duration, format_duration, format_local, now};
+
+const TICK: Duration = Duration::from_secs(5);
+
+#[derive(Subcommand, Debug)]
+pub enum HeartbeatCommand {
+    /// Record that a heartbeat is alive
+    Ping {
+        /// Name of the heartbeat in the configuration
+        name: String,
+    },
+
+    /// Push when a heartbeat misses its deadline and when it comes back, runs until stopped
+    Watch,
+
+    /// Show when each configured heartbeat was last seen
+    Status,
+}
+
+/// A heartbeat of the `heartbeats` section
+#[derive(Deserialize, Debug)]
+pub struct HeartbeatConfig {
+    /// Longest silence before the alert, e.g. 1h
+    #[serde(deserialize_with = "de_duration")]
+    deadline: Duration,
+
+    /// Devices of the alert, the default devices if empty
+    #[serde(default)]
+    devices: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Beat {
+    name: String,
+    last: u64,
+}
+
+fn dir() -> PathBuf {
+    state_dir().join("heartbeat")
+}
+
+fn beat_path(name: &str) -> PathBuf {
+    dir().join(format!("{:016x}.json", fnv1a(name.as_bytes())))
+}
+
+async fn last_ping(name: &str) -> Option<u64> {
+    match read_json::<Beat>(&beat_path(name)).await {
+        Ok(beat) => beat.map(|beat| beat.last),
+        Err(er) => {
+            eprintln!("{} {}: {}", "heartbeat".red(), name, er);
+            None
+        }
+    }
+}
+
+/// Record a ping of `name` now
+pub async fn ping(name: &str) -> Result<()> {
+    let beat = Beat {
+        name: name.to_owned(),
+        last: now(),
+    };
+    write_json(&beat_path(name), &beat).await
+}
+
+fn message(name: &str, title: String, body: String, level: &str) -> serde_json::Result<Push> {
+    let mut fields = Map::new();
+    fields.insert("title".to_owned(), title.into());
+    fields.insert("body".to_owned(), body.into());
+    fields.insert("level".to_owned(), level.into());
+    fields.insert("group".to_owned(), "heartbeat".into());
+    // The recovery replaces the alert on the phone
+    let id = format!("heartbeat-{:016x}", fnv1a(name.as_bytes()));
+    fields.insert("id".to_owned(), id.into());
+    serde_json::from_value(Value::Object(fields))
+}
+
+/// Check the heartbeats until stopped, silence is counted from the start for those never pinged
+pub async fn watch(heartbeats: &HashMap<String, HeartbeatConfig>, pusher: &Pusher) -> Result<()> {
+    let started = now();
+    let down_path = dir().join("down.json");
+    // Heartbeats that are past their deadline, since when, kept across restarts
+    let mut down = read_json::<HashMap<String, u64>>(&down_path)
+        .await?
+        .unwrap_or_default();
+    down.retain(|name, _| heartbeats.contains_key(name));
+
+    println!(
+        "{}: {}",
+        "watching heartbeats".green(),
+        heartbeats.keys().cloned().collect::<Vec<_>>().join(", ")
+    );
+    loop {
+        let mut changed = false;
+        for (name, config) in heartbeats {
+            let last = last_ping(name).await;
+            let silence = now().saturating_sub(last.unwrap_or(started));
+
+            // Down since when, or back up, once the notice is out
+            let (notice, down_since) = match down.get(name) {
+                None if silence > config.deadline.as_secs() => {
+                    let since = match last {
+                        Some(last) => format!("last ping at {}", format_local(last)),
+                        None => format!("no ping since {}", format_local(started)),
+                    };
+                    let body = format!(
+                        "silent for {}, the deadline is {}\n{since}",
+                        format_duration(Duration::from_secs(silence)),
+                        format_duration(config.deadline)
+                    );
+                    let notice = message(
+                        name,
+                        format!("{name} missed its heartbeat"),
+                        body,
+                        "critical",
+                    );
+                    (notice, Some(now()))
+                }
+                Some(since) if last.is_some_and(|last| last >= *since) => {
+                    let body = format!("pinged at {}", format_local(last.unwrap_or_default()));
+                    (
+                        message(name, format!("{name} is back"), body, "active"),
+                        None,
+                    )
+                }
+                _ => continue,
+            };
+
+            // A notice that can't be made won't be made on the next tick either
+            let sent = match notice {
+                Ok(notice) => match pusher.push(&notice, &config.devices).await {
+                    Ok(()) => {
+                        println!("{}: {}", "pushed".green(), notice.label());
+                        true
+                    }
+                    Err(er) => {
+                        eprintln!("{} {}: {}", "heartbeat push failed".red(), name, er);
+                        false
+                    }
+                },
+                Err(er) => {
+                    eprintln!("{} {}: {}", "heartbeat".red(), name, er);
+                    true
+                }
+            };
+            // A failed push is tried again on the next tick
+            if sent {
+                match down_since {
+                    Some(since) => down.insert(name.clone(), since),
+                    None => down.remove(name),
+                };
+                changed = true;
+            }
+        }
+
+        if changed {
+            write_json(&down_path, &down).await?;
+        }
+        tokio::time::sleep(TICK).await;
+    }
+}
+
+pub async fn manage(
+    command: &HeartbeatCommand,
+    heartbeats: HashMap<String, HeartbeatConfig>,
+    service: Service,
+    encryption: Encryption,
+) -> Result<()> {
+    match command {
+        HeartbeatCommand::Ping { name } => {
+            if !heartbeats.is_empty() && !heartbeats.contains_key(name) {
+                eprintln!("{}: {} is not configured", "warning".yellow(), name);
+            }
+            ping(name).await
+        }
+        HeartbeatCommand::Watch => {
+            if heartbeats.is_empty() {
+                bail!("No heartbeats in the configuration file");
+            }
+            watch(&heartbeats, &Pusher::new(service, encryption)?).await
+        }
+        HeartbeatCommand::Status => {
+            let mut names = heartbeats.keys().collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                let deadline = heartbeats[name].deadline;
+                match last_ping(name).await {
+                    Some(last) => {
+                        let silence = Duration::from_secs(now().saturating_sub(last));
+                        let state = if silence > deadline {
+                            "late".red().to_string()
+                        } else {
+                            "ok".green().to_string()
+                        };
+                        println!(
+                            "{}  {}  {}  deadline {}",
+                            name.cyan(),
+                            state,
+                            format_local(last),
+                            format_duration(deadline)
+                        );
+                    }
+                    None => println!("{}  never pinged", name.cyan()),
+                }
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use anstream::{eprintln, println};
+use anyhow::{Result, bail};
+use owo_colors::OwoColorize;
+use percent_encoding::percent_decode_str;
+use serde_json::{Map, Value, json};
+use url::Url;
+
+use crate::bark::{Encryption, Push, Service};
+use crate::send::Envelope;
+
+/// Path fields of a Bark url by the number of segments after the server
+fn path_fields(count: usize) -> Option<&'static [&'static str]> {
+    match count {
+        1 => Some(&["device_key"]),
+        2 => Some(&["device_key", "body"]),
+        3 => Some(&["device_key", "title", "body"]),
+        4 => Some(&["device_key", "title", "subtitle", "body"]),
+        _ => None,
+    }
+}
+
+/// Print the Bark url of the push for every device
+pub fn print(push: &Push, service: &Service, encryption: &Encryption) -> Result<()> {
+    let devices = service.device_keys();
+    if devices.is_empty() {
+        bail!("No device key to put in the url");
+    }
+    let envelope = Envelope::seal(push, service, devices, encryption)?;
+    for device in &envelope.devices {
+        if envelope.backends.contains_key(device) {
+            eprintln!(
+                "{}: {} is not a Bark device, skipped",
+                "warning".yellow(),
+                device
+            );
+            continue;
+        }
+        println!("{}", envelope.url(device)?);
+    }
+    Ok(())
+}
+
+/// A Bark url taken apart
+#[derive(Debug)]
+pub struct Parsed {
+    server: String,
+    key: String,
+    fields: Map<String, Value>,
+}
+
+/// Take a Bark url apart, `server` is where the server ends if the url starts with it,
+/// else the server is the origin of the url
+pub fn parse(link: &str, server: Option<&str>) -> Result<Parsed> {
+    let url = Url::parse(link)?;
+    let base = server
+        .map(|server| server.trim_end_matches('/'))
+        .filter(|server| {
+            link.strip_prefix(server)
+                .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .map(str::to_owned)
+        .unwrap_or_else(|| url.origin().ascii_serialization());
+    let path = Url::parse(&base)
+        .ok()
+        .map(|base| base.path().trim_end_matches('/').to_owned())
+        .unwrap_or_default();
+    // The text of the link starts with the server, its normalized path may not
+    let Some(rest) = url.path().strip_prefix(&path) else {
+        bail!("{link} is not a Bark url under {base}");
+    };
+
+    let segments = rest
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let Some(names) = path_fields(segments.len()) else {
+        bail!("{link} is not a Bark url, it has no device key or too many path segments");
+    };
+
+    let mut fields = Map::new();
+    for (name, value) in names.iter().zip(segments) {
+        fields.insert(name.to_string(), Value::String(value));
+    }
+    for (name, value) in url.query_pairs() {
+        fields.insert(name.into_owned(), Value::String(value.into_owned()));
+    }
+    if fields.contains_key("ciphertext") {
+        bail!("{link} is encrypted, only plain Bark urls can be read");
+    }
+    let Some(Value::String(key)) = fields.remove("device_key") else {
+        bail!("{link} has no device key");
+    };
+
+    Ok(Parsed {
+        server: base,
+        key,
+        fields,
+    })
+}
+
+impl Parsed {
+    /// The push of the url with the fields of `push` over it
+    pub fn push_under(&self, push: &Push) -> Result<Push> {
+        let mut fields = self.fields.clone();
+        if let Value::Object(own) = serde_json::to_value(push)? {
+            fields.extend(own);
+        }
+        Ok(serde_json::from_value(Value::Object(fields))?)
+    }
+
+    /// The server and the device key of the url
+    pub fn service(&self) -> Result<Service> {
+        Ok(serde_json::from_value(json!({
+            "server": self.server,
+            "device_keys": [self.key],
+        }))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(parsed: &Parsed) -> Value {
+        Value::Object(parsed.fields.clone())
+    }
+
+    #[test]
+    fn path_and_query() {
+        let parsed = parse("https://api.day.app/KEY/Deploy/done?group=ci&badge=3", None).unwrap();
+        assert_eq!(parsed.server, "https://api.day.app");
+        assert_eq!(parsed.key, "KEY");
+        assert_eq!(
+            fields(&parsed),
+            json!({ "title": "Deploy", "body": "done", "group": "ci", "badge": "3" })
+        );
+
+        let parsed = parse("https://h/KEY/a%2Fb/line%0Anext", None).unwrap();
+        assert_eq!(
+            fields(&parsed),
+            json!({ "title": "a/b", "body": "line\nnext" })
+        );
+    }
+
+    #[test]
+    fn server_with_a_path() {
+        let parsed = parse("https://h/bark/KEY/t/s/b", Some("https://h/bark/")).unwrap();
+        assert_eq!(parsed.server, "https://h/bark");
+        assert_eq!(parsed.key, "KEY");
+        assert_eq!(
+            fields(&parsed),
+            json!({ "title": "t", "subtitle": "s", "body": "b" })
+        );
+
+        // A link elsewhere on the host is taken from the origin
+        let parsed = parse("https://h/KEY/b", Some("https://h/bark")).unwrap();
+        assert_eq!(
+            (parsed.server.as_str(), parsed.key.as_str()),
+            ("https://h", "KEY")
+        );
+    }
+
+    #[test]
+    fn not_bark_urls() {
+        assert!(parse("https://h/bark/..", Some("https://h/bark")).is_err());
+        assert!(parse("https://h/bark/../KEY", Some("https://h/bark")).is_err());
+        assert!(parse("https://h/", None).is_err());
+        assert!(parse("https://h/k/t/s/b/more", None).is_err());
+        assert!(parse("https://h/KEY?ciphertext=abc", None).is_err());
+        assert!(parse("not a url", None).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let service = serde_json::from_value::<Service>(json!({
+            "server": "https://h/bark",
+            "device_keys": ["KEY"],
+        }))
+        .unwrap();
+        let encryption = serde_json::from_value::<Encryption>(json!({})).unwrap();
+        let original = json!({
+            "title": "a/b ?&=#%+",
+            "subtitle": "😀 non-BMP",
+            "body": "first line\nsecond line",
+            "group": "ci/cd",
+            "url": "https://example.com/?q=1&r=2",
+            "level": "timeSensitive",
+            "call": "1",
+        });
+        let push = serde_json::from_value::<Push>(original).unwrap();
+
+        let envelope = Envelope::seal(&push, &service, service.device_keys(), &encryption).unwrap();
+        let link = envelope.url("KEY").unwrap();
+        let parsed = parse(link.as_str(), Some(service.server())).unwrap();
+        assert_eq!(parsed.server, "https://h/bark");
+        assert_eq!(parsed.key, "KEY");
+
+        let empty = serde_json::from_value::<Push>(json!({})).unwrap();
+        let read = parsed.push_under(&empty).unwrap();
+        assert_eq!(
+            serde_json::to_value(&read).unwrap(),
+            serde_json::to_value(&push).unwrap()
+        );
+    }
+}
@@ -1,41 +1,46 @@
+mod backend;
 mod bark;
 mod command;
+mod dedup;
+mod device;
+mod edit;
+mod escalate;
+mod heartbeat;
+mod link;
+mod monitor;
+mod output;
+mod quiet;
+mod route;
+mod schedule;
+mod send;
+mod serve;
+mod server;
+mod smtp;
+mod spool;
+mod state;
+mod syslog;
+mod template;
+mod time;
+mod wait_pid;
+mod watch_log;
+mod watch_path;
 
 use std::path::Path;
 
 use anstream::{eprintln, println};
 use anyhow::Result;
 use clap::Parser;
+use jiff::Timestamp;
 use owo_colors::OwoColorize;
-use reqwest::{
-    Client, StatusCode,
-    header::{self, HeaderMap, HeaderValue},
-};
-use serde::Deserialize;
 use tokio::fs;
 
 use crate::bark::Configuration;
-use crate::command::Cli;
+use crate::command::{Cli, Commands};
+use crate::output::Report;
+use crate::send::{Envelope, Pusher};
 
 static API_SERVER: &str = "https://api.day.app";
 
-#[derive(Deserialize, Debug)]
-struct Resp {
-    code: u16,
-    message: String,
-    #[allow(unused)]
-    timestamp: u64,
-}
-
-fn urlencoding(s: impl Into<String>) -> String {
-    s.into()
-        .replace('+', "%2B")
-        .replace('/', "%2F")
-        .replace('=', "%3D")
-        .replace(' ', "%20")
-        .replace('&', "%26")
-}
-
 fn hide_str(s: impl AsRef<str>) -> String {
     let s = s.as_ref();
     if s.len() < 10 {
@@ -59,19 +64,32 @@ async fn get_command() -> Result<Cli> {
     #[cfg(debug_assertions)]
     println!("{:#?}", cli);
 
-    if !cli.thats_all {
-        if let Some(path) = cli.config_file() {
-            let configuration = read_config(path).await?;
-            #[cfg(debug_assertions)]
-            println!("{:#?}", configuration);
-
-            cli.service.merge(configuration.service);
-            cli.push.update_storable(configuration.stored);
-            cli.encryption
-                .merge(configuration.encryption, cli.no_encrypt);
-            cli.push.update_level(cli.level());
-            cli.push.update_archive(cli.archive());
-        }
+    let configuration = match cli.config_file() {
+        Some(path) if !cli.thats_all => Some(read_config(path).await?),
+        _ => None,
+    };
+
+    // The url goes under the other options and over the configuration
+    if let Some(from_url) = &cli.from_url {
+        let server = cli.service.configured_server().or(configuration
+            .as_ref()
+            .and_then(|configuration| configuration.service.configured_server()));
+        let parsed = link::parse(from_url, server)?;
+        cli.push = parsed.push_under(&cli.push)?;
+        cli.service.merge(parsed.service()?);
+    }
+
+    if let Some(configuration) = configuration {
+        #[cfg(debug_assertions)]
+        println!("{:#?}", configuration);
+
+        cli.service.merge(configuration.service);
+        cli.push.update_storable(configuration.stored);
+        cli.encryption
+            .merge(configuration.encryption, cli.no_encrypt);
+        cli.push.update_level(cli.level());
+        cli.push.update_archive(cli.archive());
+        cli.sections = configuration.sections;
     }
 
     Ok(cli)
@@ -82,92 +100,190 @@ async fn run_command() -> Result<()> {
     #[cfg(debug_assertions)]
     println!("{:#?}", cli);
 
-    let push = json5::to_string(&cli.push)?;
+    match cli.command {
+        Some(Commands::Serve(ref args)) => {
+            serve::serve(
+                args,
+                cli.sections,
+                cli.service,
+                cli.encryption,
+                cli.queue_on_failure.then_some(cli.outbox),
+            )
+            .await
+        }
+        Some(Commands::Smtp(ref args)) => {
+            smtp::serve(args, cli.sections.smtp, cli.service, cli.encryption).await
+        }
+        Some(Commands::Syslog(ref args)) => {
+            syslog::serve(args, cli.sections.syslog, cli.service, cli.encryption).await
+        }
+        Some(Commands::WatchLog(ref args)) => {
+            watch_log::watch(args, cli.sections.watch_log, cli.service, cli.encryption).await
+        }
+        Some(Commands::WatchPath(ref args)) => {
+            watch_path::watch(args, cli.sections.watch_path, cli.service, cli.encryption).await
+        }
+        Some(Commands::WaitPid(ref args)) => {
+            wait_pid::wait(args, cli.service, cli.encryption).await
+        }
+        Some(Commands::Monitor(ref args)) => {
+            monitor::monitor(args, cli.service, cli.encryption).await
+        }
+        Some(Commands::Device { ref command }) => {
+            let config = cli.config_file().map(Path::to_path_buf);
+            device::manage(command, config.as_deref(), cli.service, cli.encryption).await
+        }
+        Some(Commands::Server { ref command }) => server::manage(command, &cli.service).await,
+        Some(Commands::Url) => link::print(&cli.push, &cli.service, &cli.encryption),
+        Some(Commands::Ack { ref ids, list }) => {
+            for id in ids {
+                escalate::ack(id).await?;
+                println!("{}: {}", "acknowledged".green(), id);
+            }
+            if list {
+                escalate::list().await?;
+            }
+            Ok(())
+        }
+        Some(Commands::Heartbeat { ref command }) => {
+            heartbeat::manage(
+                command,
+                cli.sections.heartbeats,
+                cli.service,
+                cli.encryption,
+            )
+            .await
+        }
+        Some(Commands::Flush) => cli.outbox.flush(&send::client()?, &cli.service).await,
+        Some(Commands::Scheduler) => {
+            let client = send::client()?;
+            let scheduled = schedule::run(&client, &cli.outbox, &cli.service);
+            if cli.sections.escalations.is_empty() {
+                return scheduled.await;
+            }
+            let pusher = Pusher::new(cli.service.clone(), cli.encryption)?;
+            let escalations = escalate::run(&pusher, &cli.sections.escalations);
+            tokio::try_join!(scheduled, escalations).map(|_| ())
+        }
+        Some(Commands::Schedule { ref command }) => schedule::manage(command).await,
+        None => push(cli).await,
+    }
+}
+
+async fn push(cli: Cli) -> Result<()> {
+    let routing = route::route(&cli.service, &cli.push, &cli.tags.tags);
+    let mut devices = cli.service.device_keys();
+    routing.add_to(&mut devices);
 
-    let push = if cli.encryption.encrypted() {
-        cli.encryption.encrypt(&push)?
-    } else {
-        push
-    };
+    let verdict = cli
+        .throttle
+        .check(&json5::to_string(&cli.push)?, devices.clone(), cli.dry_run)
+        .await?;
 
-    let devices = cli.service.device_keys();
-    if cli.dry_run {
-        println!(
-            "Will push to {}: {}",
-            cli.service.server().cyan().italic(),
-            devices
-                .iter()
-                .map(|dev| format!("{}", hide_str(dev).blue()))
-                .collect::<Vec<_>>()
-                .join(", ")
-        );
-        println!("{}", push.green());
-    } else {
-        if devices.is_empty() {
-            return Ok(());
-        }
-        let client = Client::builder()
-            .default_headers({
-                let mut headers = HeaderMap::new();
-                headers.insert(header::USER_AGENT, HeaderValue::from_static("reqwest/0.12"));
-                if cli.encryption.encrypted() {
-                    headers.insert(
-                        header::CONTENT_TYPE,
-                        HeaderValue::from_static("application/x-www-form-urlencoded"),
-                    );
-                } else {
-                    headers.insert(
-                        header::CONTENT_TYPE,
-                        HeaderValue::from_static("application/json; charset=utf-8"),
-                    );
-                }
-                headers
-            })
-            .build()?;
-
-        let body = if cli.encryption.encrypted() {
-            format!("ciphertext={}", urlencoding(&push))
-        } else {
-            push
-        };
-
-        let mut handlers = Vec::with_capacity(devices.len());
-        for dev in devices {
-            let url = url::Url::parse(cli.service.server())?.join(dev)?;
-            let client = client.clone();
-            let body = body.clone();
-
-            let handle = tokio::spawn(async move {
-                let resp = client
-                    .post(url)
-                    .body(body)
-                    .send()
-                    .await?
-                    .json::<Resp>()
-                    .await?;
-
-                #[cfg(debug_assertions)]
-                println!("{:#?}", resp);
-
-                let status = StatusCode::from_u16(resp.code)?;
-                if status.is_success() {
-                    println!("{}", resp.message.green());
-                } else {
-                    eprintln!("{}", resp.message.red());
-                }
-                Result::<_>::Ok(())
-            });
-            handlers.push(handle);
+    // The summary goes out when the window closes, it is rewritten with every duplicate
+    if let (Some(count), Some(due), Some(key)) =
+        (verdict.suppressed, verdict.window_ends, &verdict.key)
+        && cli.throttle.summary
+        && !cli.dry_run
+    {
+        let summary = cli.push.with_body(format!(
+            "{count} duplicate pushes were suppressed since the last one"
+        ));
+        let envelope = Envelope::seal(&summary, &cli.service, devices, &cli.encryption)?;
+        let id = format!("summary-{:016x}", state::fnv1a(key.as_bytes()));
+        schedule::put(&id, due, summary.label(), &envelope, cli.queue_on_failure).await?;
+    }
+
+    let due = cli.when.due();
+    if let Some(policy) = &cli.escalate.escalate
+        && !cli.dry_run
+        && verdict.suppressed.is_none()
+    {
+        // With --wait the escalation starts after the wait, else it starts at the due time
+        let at = due.filter(|_| !cli.when.wait);
+        if let Some(due) = due
+            && cli.when.wait
+        {
+            schedule::wait_until(due).await;
         }
+        let pusher = Pusher::new(cli.service.clone(), cli.encryption.clone())?;
+        let (started, results) =
+            escalate::start(policy, &cli.push, &cli.sections.escalations, &pusher, at).await?;
+        let envelope = Envelope::seal(&cli.push, &cli.service, verdict.allowed, &cli.encryption)?;
+        let mut report = Report::new(&cli.push, &envelope, cli.encryption.mode(), false, results);
+        report.due = at;
+        report.routes = routing.notes;
+        report.escalation = Some(started);
+        return report.print(cli.output);
+    }
+
+    let live = !cli.dry_run && verdict.suppressed.is_none();
+    // With --wait the push is sent after the wait, else it is scheduled for the due time
+    let later = due.filter(|_| !cli.when.wait);
+    if let Some(due) = due
+        && cli.when.wait
+        && live
+        && !verdict.allowed.is_empty()
+    {
+        schedule::wait_until(due).await;
+    }
+
+    // Quiet hours apply when the push is due, not when it is scheduled
+    let at = match later {
+        Some(due) => Timestamp::from_second(due as i64)?,
+        None => Timestamp::now(),
+    };
+    let quiet = quiet::plan_at(&cli.service, &cli.push, verdict.allowed.clone(), at);
+    let envelope = Envelope::seal(&cli.push, &cli.service, quiet.loud.clone(), &cli.encryption)?;
+    let hushed = quiet.hushed(&cli.push, &cli.service, &cli.encryption)?;
 
-        for handle in handlers {
-            if let Err(er) = handle.await? {
-                eprintln!("{}: {}", "error in sending message".red(), er);
+    let (mut results, mut scheduled, mut deferred) = (Vec::new(), Vec::new(), Vec::new());
+    if live {
+        deferred = quiet
+            .defer(
+                &cli.push,
+                &cli.service,
+                &cli.encryption,
+                cli.queue_on_failure,
+            )
+            .await?;
+
+        let client = send::client()?;
+        for envelope in [Some(&envelope), hushed.as_ref()] {
+            let Some(envelope) = envelope.filter(|envelope| !envelope.devices.is_empty()) else {
+                continue;
+            };
+            if let Some(due) = later {
+                let label = cli.push.label();
+                scheduled.push(schedule::add(due, label, envelope, cli.queue_on_failure).await?);
+                continue;
+            }
+            let delivered = envelope.deliver(&client).await?;
+            if cli.queue_on_failure
+                && let Some(path) = cli.outbox.queue(envelope, &delivered).await?
+            {
+                eprintln!("{}: {}", "queued for retry".yellow(), path.display());
             }
+            results.extend(delivered);
         }
+        cli.throttle.record(&verdict, &results).await?;
     }
 
-    Ok(())
+    let mut report = Report::new(
+        &cli.push,
+        &envelope,
+        cli.encryption.mode(),
+        cli.dry_run,
+        results,
+    );
+    report.due = due;
+    report.suppressed = verdict.suppressed;
+    report.rate_limited = verdict.limited.iter().map(hide_str).collect();
+    report.routes = routing.notes;
+    report.quiet = quiet.notes;
+    report.scheduled = scheduled;
+    report.deferred = deferred;
+    report.print(cli.output)
 }
 
 #[tokio::main]
@@ -0,0 +1,287 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use anstream::{eprintln, println};
+use anyhow::{Result, bail};
+use clap::Args;
+use owo_colors::OwoColorize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::bark::{Encryption, Service};
+use crate::send::Pusher;
+use crate::state::{fnv1a, read_json, state_dir, write_json};
+use crate::template::PushTemplate;
+use crate::time::{format_duration, now, parse_period};
+
+/// The first number of an output, without a group so that all of it is the value
+static NUMBER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"-?\d+(?:\.\d+)?").unwrap());
+
+#[derive(Args, Debug)]
+pub struct MonitorArgs {
+    /// Run the command this often, e.g. 5m
+    #[arg(long, value_name = "DURATION", value_parser = parse_period, required_unless_present = "once")]
+    every: Option<Duration>,
+
+    /// Run the command once and exit, for cron, the state is kept between runs
+    #[arg(long)]
+    once: bool,
+
+    /// Name of the monitor in pushes and its state, default is the command line
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Push when the value goes above this
+    #[arg(long, value_name = "NUMBER", allow_negative_numbers = true)]
+    above: Option<f64>,
+
+    /// Push when the value goes below this
+    #[arg(long, value_name = "NUMBER", allow_negative_numbers = true)]
+    below: Option<f64>,
+
+    /// Regular expression whose first group is the value, default is the first number of the output
+    #[arg(long, value_name = "REGEX")]
+    value: Option<Regex>,
+
+    /// Don't push when only the output changed
+    #[arg(long)]
+    ignore_output: bool,
+
+    /// Title template of the pushes, default is the name and the event
+    #[arg(long, value_name = "TEMPLATE")]
+    title: Option<String>,
+
+    /// Body template of the pushes, default is the reason and the output
+    #[arg(long, value_name = "TEMPLATE")]
+    body: Option<String>,
+
+    /// The command and its arguments
+    #[arg(last = true, required = true, value_name = "COMMAND")]
+    command: Vec<String>,
+}
+
+/// What the last run saw, kept in the state directory
+#[derive(Serialize, Deserialize, Debug)]
+struct State {
+    output: String,
+    status: Option<i32>,
+    value: Option<f64>,
+    alert: bool,
+    checked: u64,
+}
+
+impl MonitorArgs {
+    fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.command.join(" "))
+    }
+
+    fn has_threshold(&self) -> bool {
+        self.above.is_some() || self.below.is_some()
+    }
+
+    fn parse_value(&self, output: &str) -> Option<f64> {
+        let pattern = self.value.as_ref().unwrap_or(&NUMBER);
+        let found = pattern.captures(output)?;
+        let text = found.get(1).or(found.get(0))?.as_str();
+        text.trim().parse().ok()
+    }
+
+    /// Why the value is alerting, if it is
+    fn breach(&self, value: Option<f64>) -> Option<String> {
+        if !self.has_threshold() {
+            return None;
+        }
+        let Some(value) = value else {
+            return Some("no value in the output".to_owned());
+        };
+        if let Some(above) = self.above
+            && value > above
+        {
+            return Some(format!("value {value} is above {above}"));
+        }
+        if let Some(below) = self.below
+            && value < below
+        {
+            return Some(format!("value {value} is below {below}"));
+        }
+        None
+    }
+}
+
+fn status_text(status: Option<i32>) -> String {
+    status.map_or("no exit status".to_owned(), |status| {
+        format!("exit status {status}")
+    })
+}
+
+async fn execute(command: &[String]) -> (String, Option<i32>) {
+    let output = Command::new(&command[0])
+        .args(&command[1..])
+        .kill_on_drop(true)
+        .output()
+        .await;
+    match output {
+        Ok(output) => {
+            let mut text = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+            if text.is_empty() {
+                text = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+            }
+            (text, output.status.code())
+        }
+        Err(er) => (format!("{}: {er}", command[0]), None),
+    }
+}
+
+/// Run the command once, push what changed since the last run
+async fn check(
+    args: &MonitorArgs,
+    pusher: &Pusher,
+    template: &PushTemplate,
+    name: &str,
+) -> Result<()> {
+    let path = state_dir()
+        .join("monitor")
+        .join(format!("{:016x}.json", fnv1a(name.as_bytes())));
+    let last = read_json::<State>(&path).await?;
+
+    let (output, status) = execute(&args.command).await;
+    let value = args.parse_value(&output);
+    let reason = match args.breach(value) {
+        Some(breach) => Some(breach),
+        None if status != Some(0) => Some(status_text(status)),
+        None => None,
+    };
+    let state = State {
+        output,
+        status,
+        value,
+        alert: reason.is_some(),
+        checked: now(),
+    };
+
+    let event = match (&last, &reason) {
+        (None, Some(reason)) => Some(("alert", reason.clone())),
+        (None, None) => None,
+        (Some(last), Some(reason)) if !last.alert => Some(("alert", reason.clone())),
+        (Some(last), None) if last.alert => {
+            let reason = if args.has_threshold() && last.status == Some(0) {
+                format!("value {} is back in range", value.unwrap_or_default())
+            } else {
+                status_text(status)
+            };
+            Some(("recovered", reason))
+        }
+        (Some(last), _) if last.status != status => Some((
+            "changed",
+            format!("{} was {}", status_text(status), status_text(last.status)),
+        )),
+        (Some(last), _)
+            if !args.ignore_output && !args.has_threshold() && last.output != state.output =>
+        {
+            Some(("changed", "output changed".to_owned()))
+        }
+        _ => None,
+    };
+
+    if let Some((event, reason)) = event {
+        println!("{} {}: {}", name, event.yellow(), reason);
+        let lookup = |var: &str| match var {
+            "name" => Some(name.to_owned()),
+            "command" => Some(args.command.join(" ")),
+            "event" => Some(event.to_owned()),
+            "reason" => Some(reason.clone()),
+            "output" => Some(state.output.clone()),
+            "status" => state.status.map(|status| status.to_string()),
+            "value" => state.value.map(|value| value.to_string()),
+            _ => None,
+        };
+        let push = template.render(lookup)?;
+        pusher.push(&push, &[]).await?;
+    }
+
+    write_json(&path, &state).await
+}
+
+pub async fn monitor(args: &MonitorArgs, service: Service, encryption: Encryption) -> Result<()> {
+    if args.command.is_empty() {
+        bail!("No command to monitor");
+    }
+    let mut template = PushTemplate::default();
+    template.or_insert(
+        "title",
+        args.title.as_deref().unwrap_or("{{ name }}: {{ event }}"),
+    );
+    template.or_insert(
+        "body",
+        args.body.as_deref().unwrap_or("{{ reason }}\n{{ output }}"),
+    );
+    let pusher = Pusher::new(service, encryption)?;
+    let name = args.name();
+
+    let Some(every) = args.every.filter(|_| !args.once) else {
+        return check(args, &pusher, &template, &name).await;
+    };
+    println!(
+        "{}: {} every {}",
+        "monitoring".green(),
+        name,
+        format_duration(every)
+    );
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        if let Err(er) = check(args, &pusher, &template, &name).await {
+            eprintln!("{}: {}", "monitor".red(), er);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        monitor: MonitorArgs,
+    }
+
+    fn args(flags: &[&str]) -> MonitorArgs {
+        let argv = ["barsk", "--once"]
+            .iter()
+            .chain(flags)
+            .chain(&["--", "true"]);
+        Cli::parse_from(argv).monitor
+    }
+
+    #[test]
+    fn default_value_is_the_first_number() {
+        let monitor = args(&[]);
+        assert_eq!(monitor.parse_value("load 85.5 of 100"), Some(85.5));
+        assert_eq!(monitor.parse_value("42 items"), Some(42.0));
+        assert_eq!(monitor.parse_value("at -3.25C"), Some(-3.25));
+        assert_eq!(monitor.parse_value("no number"), None);
+    }
+
+    #[test]
+    fn value_pattern_with_a_group() {
+        let monitor = args(&["--value", r"used (\d+(?:\.\d+)?)%"]);
+        assert_eq!(monitor.parse_value("disk 2 used 91.5% of 3"), Some(91.5));
+        let monitor = args(&["--value", r"\d+\.\d+"]);
+        assert_eq!(monitor.parse_value("temp=21.5"), Some(21.5));
+    }
+
+    #[test]
+    fn decimal_thresholds() {
+        let monitor = args(&["--above", "80"]);
+        let value = monitor.parse_value("85.5");
+        assert_eq!(
+            monitor.breach(value).as_deref(),
+            Some("value 85.5 is above 80")
+        );
+        assert_eq!(monitor.breach(monitor.parse_value("79.9")), None);
+    }
+}
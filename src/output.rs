@@ -0,0 +1,170 @@
+use anstream::{eprintln, println};
+use anyhow::Result;
+use clap::ValueEnum;
+use owo_colors::OwoColorize;
+use serde::Serialize;
+
+use crate::bark::Push;
+use crate::escalate::Started;
+use crate::send::{Delivery, Envelope};
+use crate::time::format_local;
+
+#[derive(ValueEnum, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Colored text for terminals
+    #[default]
+    Human,
+    /// One pretty printed JSON document
+    Json,
+    /// One compact JSON document per line
+    Jsonl,
+}
+
+/// What is about to be sent, or what has been sent, in one invocation.
+#[derive(Serialize, Debug)]
+pub struct Report<'a> {
+    pub dry_run: bool,
+    pub server: &'a str,
+    pub devices: Vec<String>,
+    pub encryption: Option<&'static str>,
+    pub push: &'a Push,
+    pub payload: &'a str,
+    pub results: Vec<Delivery>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub due: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suppressed: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rate_limited: Vec<String>,
+    /// Routes that matched the push
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<String>,
+    /// What quiet hours changed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quiet: Vec<String>,
+    /// Ids of the pushes put in the schedule for `due`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub scheduled: Vec<String>,
+    /// Ids of the pushes deferred to the end of quiet hours
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deferred: Vec<String>,
+    /// The escalation the push started, `results` are of its first step
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub escalation: Option<Started>,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(
+        push: &'a Push,
+        envelope: &'a Envelope,
+        encryption: Option<&'static str>,
+        dry_run: bool,
+        results: Vec<Delivery>,
+    ) -> Self {
+        Self {
+            dry_run,
+            server: &envelope.server,
+            devices: envelope.devices.iter().map(crate::hide_str).collect(),
+            encryption,
+            push,
+            payload: &envelope.payload,
+            results,
+            due: None,
+            suppressed: None,
+            rate_limited: Vec::new(),
+            routes: Vec::new(),
+            quiet: Vec::new(),
+            scheduled: Vec::new(),
+            deferred: Vec::new(),
+            escalation: None,
+        }
+    }
+
+    pub fn print(&self, format: OutputFormat) -> Result<()> {
+        match format {
+            OutputFormat::Human => self.print_human(),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+            OutputFormat::Jsonl => println!("{}", serde_json::to_string(self)?),
+        }
+        Ok(())
+    }
+
+    fn print_human(&self) {
+        if let Some(count) = self.suppressed {
+            eprintln!(
+                "{}: {} suppressed in this window",
+                "duplicate push".yellow(),
+                count
+            );
+            return;
+        }
+        if !self.rate_limited.is_empty() {
+            eprintln!(
+                "{}: {}",
+                "rate limited".yellow(),
+                self.rate_limited.join(", ")
+            );
+        }
+        for note in &self.routes {
+            eprintln!("{}: {}", "routed".cyan(), note);
+        }
+        for note in &self.quiet {
+            eprintln!("{}: {}", "quiet hours".yellow(), note);
+        }
+        if let Some(escalation) = &self.escalation {
+            let from = self
+                .due
+                .map(|due| format!(" from {}", format_local(due)))
+                .unwrap_or_default();
+            println!(
+                "{}: {} by {}{from}",
+                "escalating".green(),
+                escalation.id.cyan(),
+                escalation.policy
+            );
+        }
+
+        if self.dry_run {
+            println!(
+                "Will push to {}: {}",
+                self.server.cyan().italic(),
+                self.devices
+                    .iter()
+                    .map(|dev| format!("{}", dev.blue()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            println!("{}", self.payload.green());
+            if let Some(due) = self.due {
+                println!("At {}", format_local(due).cyan());
+            }
+            return;
+        }
+
+        if let Some(due) = self.due {
+            for id in &self.scheduled {
+                println!(
+                    "{}: {} at {}",
+                    "scheduled".green(),
+                    id.cyan(),
+                    format_local(due)
+                );
+            }
+        }
+        for id in &self.deferred {
+            println!("{}: {}", "deferred".green(), id.cyan());
+        }
+        print_results(&self.results);
+    }
+}
+
+pub fn print_results(results: &[Delivery]) {
+    for delivery in results {
+        match (&delivery.error, &delivery.message) {
+            (Some(er), _) => eprintln!("{}: {}", "error in sending message".red(), er),
+            (None, Some(message)) if delivery.is_success() => println!("{}", message.green()),
+            (None, Some(message)) => eprintln!("{}", message.red()),
+            (None, None) => {}
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use anyhow::Result;
+use jiff::{Timestamp, Zoned, civil, tz::TimeZone};
+use serde::{Deserialize, de};
+
+use crate::bark::{Encryption, Level, Push, Service};
+use crate::schedule;
+use crate::send::Envelope;
+use crate::time::format_local;
+
+/// A window of the `quiet_hours` list in the configuration file
+#[derive(Deserialize, Clone, Debug)]
+pub struct QuietHours {
+    /// Device names, group names or keys, every device if empty
+    #[serde(default)]
+    devices: Vec<String>,
+
+    /// Start of the window, e.g. "22:00"
+    #[serde(deserialize_with = "de_time")]
+    from: civil::Time,
+
+    /// End of the window, e.g. "07:00", the next day if before the start
+    #[serde(deserialize_with = "de_time")]
+    to: civil::Time,
+
+    /// Time zone of the window like "Europe/Berlin", the system time zone if missing
+    #[serde(default, deserialize_with = "de_option_time_zone")]
+    timezone: Option<TimeZone>,
+
+    /// What happens to pushes in the window
+    #[serde(default)]
+    action: QuietAction,
+
+    /// Let critical pushes through, default is true
+    #[serde(default = "yes")]
+    exempt_critical: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuietAction {
+    /// Send passive and without sound
+    #[default]
+    Downgrade,
+    /// Send at the end of the window
+    Defer,
+}
+
+fn yes() -> bool {
+    true
+}
+
+fn de_time<'de, D>(deserializer: D) -> Result<civil::Time, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .trim()
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn de_option_time_zone<'de, D>(deserializer: D) -> Result<Option<TimeZone>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|name| TimeZone::get(&name).map_err(de::Error::custom))
+        .transpose()
+}
+
+impl QuietHours {
+    /// End of the window if `now` is in it
+    fn ends(&self, now: Timestamp) -> Option<Timestamp> {
+        let tz = self.timezone.clone().unwrap_or_else(TimeZone::system);
+        let now = now.to_zoned(tz);
+        let time = now.time();
+        let today = |t: civil::Time| now.date().to_datetime(t).to_zoned(now.time_zone().clone());
+
+        let end: Result<Zoned, jiff::Error> = if self.from < self.to {
+            if time < self.from || time >= self.to {
+                return None;
+            }
+            today(self.to)
+        } else if self.from > self.to {
+            if time >= self.from {
+                now.date()
+                    .tomorrow()
+                    .and_then(|date| date.to_datetime(self.to).to_zoned(now.time_zone().clone()))
+            } else if time < self.to {
+                today(self.to)
+            } else {
+                return None;
+            }
+        } else {
+            return None;
+        };
+        end.ok().map(|end| end.timestamp())
+    }
+}
+
+/// How quiet hours split the devices of a push
+#[derive(Default, Debug)]
+pub struct Plan {
+    /// Devices that get the push as it is
+    pub loud: Vec<String>,
+    /// Devices that get it passive and without sound
+    pub hushed: Vec<String>,
+    /// Devices that get it at the end of their window, in seconds since the unix epoch
+    pub deferred: Vec<(u64, Vec<String>)>,
+    /// What quiet hours changed, for the report
+    pub notes: Vec<String>,
+}
+
+/// Split `devices` by the quiet hours they are in now
+pub fn plan(service: &Service, push: &Push, devices: Vec<String>) -> Plan {
+    plan_at(service, push, devices, Timestamp::now())
+}
+
+/// Split `devices` by the quiet hours they are in at `now`
+pub fn plan_at(service: &Service, push: &Push, devices: Vec<String>, now: Timestamp) -> Plan {
+    let critical = matches!(push.level(), Some(Level::Critical));
+    let mut plan = Plan::default();
+
+    for device in devices {
+        let window = service.quiet_hours().iter().find_map(|quiet| {
+            let covers =
+                quiet.devices.is_empty() || service.resolve(&quiet.devices).contains(&device);
+            if !covers || (critical && quiet.exempt_critical) {
+                return None;
+            }
+            quiet.ends(now).map(|ends| (quiet.action, ends))
+        });
+        let Some((action, ends)) = window else {
+            plan.loud.push(device);
+            continue;
+        };
+
+        let ends = ends.as_second().max(0) as u64;
+        let hidden = crate::hide_str(&device);
+        match action {
+            QuietAction::Downgrade => {
+                plan.notes.push(format!(
+                    "{hidden} quiet until {}, sent passive",
+                    format_local(ends)
+                ));
+                plan.hushed.push(device);
+            }
+            QuietAction::Defer => {
+                plan.notes.push(format!(
+                    "{hidden} quiet until {}, deferred",
+                    format_local(ends)
+                ));
+                match plan.deferred.iter_mut().find(|(due, _)| *due == ends) {
+                    Some((_, devices)) => devices.push(device),
+                    None => plan.deferred.push((ends, vec![device])),
+                }
+            }
+        }
+    }
+    plan
+}
+
+impl Plan {
+    /// The hushed push sealed for its devices, if there are any
+    pub fn hushed(
+        &self,
+        push: &Push,
+        service: &Service,
+        encryption: &Encryption,
+    ) -> Result<Option<Envelope>> {
+        if self.hushed.is_empty() {
+            return Ok(None);
+        }
+        let envelope = Envelope::seal(&push.hushed(), service, self.hushed.clone(), encryption)?;
+        Ok(Some(envelope))
+    }
+
+    /// The pushes to send now, as they are and hushed, for the devices that get them
+    pub fn seal(
+        &self,
+        push: &Push,
+        service: &Service,
+        encryption: &Encryption,
+    ) -> Result<Vec<Envelope>> {
+        let mut envelopes = Vec::new();
+        if !self.loud.is_empty() {
+            envelopes.push(Envelope::seal(
+                push,
+                service,
+                self.loud.clone(),
+                encryption,
+            )?);
+        }
+        envelopes.extend(self.hushed(push, service, encryption)?);
+        Ok(envelopes)
+    }
+
+    /// Put the deferred devices in the schedule, `barsk scheduler` sends them
+    pub async fn defer(
+        &self,
+        push: &Push,
+        service: &Service,
+        encryption: &Encryption,
+        queue_on_failure: bool,
+    ) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for (due, devices) in &self.deferred {
+            let envelope = Envelope::seal(push, service, devices.clone(), encryption)?;
+            ids.push(schedule::add(*due, push.label(), &envelope, queue_on_failure).await?);
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn service(quiet_hours: Value) -> Service {
+        serde_json::from_value(json!({
+            "devices": { "phone": "P", "tablet": "T" },
+            "quiet_hours": quiet_hours,
+        }))
+        .unwrap()
+    }
+
+    fn push(level: Option<&str>) -> Push {
+        serde_json::from_value(json!({ "body": "b", "level": level })).unwrap()
+    }
+
+    fn at(time: &str) -> Timestamp {
+        time.parse().unwrap()
+    }
+
+    fn devices() -> Vec<String> {
+        vec!["P".to_owned(), "T".to_owned()]
+    }
+
+    #[test]
+    fn overnight_window() {
+        let service = service(json!([
+            { "from": "22:00", "to": "07:00", "timezone": "UTC", "action": "defer" },
+        ]));
+        let push = push(None);
+
+        let late = plan_at(&service, &push, devices(), at("2026-01-15T23:30:00Z"));
+        let tomorrow = at("2026-01-16T07:00:00Z").as_second() as u64;
+        assert_eq!(late.deferred, [(tomorrow, devices())]);
+        assert!(late.loud.is_empty());
+
+        let early = plan_at(&service, &push, devices(), at("2026-01-16T06:59:00Z"));
+        assert_eq!(early.deferred, [(tomorrow, devices())]);
+
+        for time in [
+            "2026-01-16T07:00:00Z",
+            "2026-01-16T12:00:00Z",
+            "2026-01-16T21:59:00Z",
+        ] {
+            let day = plan_at(&service, &push, devices(), at(time));
+            assert_eq!(day.loud, devices(), "{time}");
+            assert!(day.deferred.is_empty() && day.notes.is_empty());
+        }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let service = service(json!([{ "from": "12:00", "to": "14:00", "timezone": "UTC" }]));
+        let push = push(None);
+
+        let lunch = plan_at(&service, &push, devices(), at("2026-01-15T13:00:00Z"));
+        assert_eq!(lunch.hushed, devices());
+        assert_eq!(lunch.notes.len(), 2);
+        let evening = plan_at(&service, &push, devices(), at("2026-01-15T23:00:00Z"));
+        assert_eq!(evening.loud, devices());
+    }
+
+    #[test]
+    fn time_zone_of_the_window() {
+        let service = service(json!([
+            { "from": "22:00", "to": "07:00", "timezone": "Asia/Tokyo", "action": "defer" },
+        ]));
+        let push = push(None);
+
+        // 23:00 in Tokyo, the window ends at 07:00 there, 22:00 UTC
+        let night = plan_at(&service, &push, devices(), at("2026-01-15T14:00:00Z"));
+        let ends = at("2026-01-15T22:00:00Z").as_second() as u64;
+        assert_eq!(night.deferred, [(ends, devices())]);
+
+        // 21:00 in Tokyo
+        let evening = plan_at(&service, &push, devices(), at("2026-01-15T12:00:00Z"));
+        assert_eq!(evening.loud, devices());
+    }
+
+    #[test]
+    fn critical_pushes() {
+        let exempt = service(json!([{ "from": "22:00", "to": "07:00", "timezone": "UTC" }]));
+        let strict = service(json!([
+            { "from": "22:00", "to": "07:00", "timezone": "UTC", "exempt_critical": false },
+        ]));
+        let night = at("2026-01-15T23:00:00Z");
+
+        let plan = plan_at(&exempt, &push(Some("critical")), devices(), night);
+        assert_eq!(plan.loud, devices());
+        let plan = plan_at(&exempt, &push(Some("timeSensitive")), devices(), night);
+        assert_eq!(plan.hushed, devices());
+        let plan = plan_at(&strict, &push(Some("critical")), devices(), night);
+        assert_eq!(plan.hushed, devices());
+    }
+
+    #[test]
+    fn devices_of_the_window() {
+        let service = service(json!([
+            { "devices": ["phone"], "from": "22:00", "to": "07:00", "timezone": "UTC" },
+        ]));
+        let plan = plan_at(&service, &push(None), devices(), at("2026-01-15T23:00:00Z"));
+        assert_eq!(plan.hushed, ["P"]);
+        assert_eq!(plan.loud, ["T"]);
+    }
+}
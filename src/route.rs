@@ -0,0 +1,192 @@
+use clap::Args;
+use serde::Deserialize;
+
+use crate::bark::{Level, Push, Service};
+
+#[derive(Args, Debug)]
+pub struct Tags {
+    /// Tag the push for the routes of the configuration, repeat for more tags
+    #[arg(long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+}
+
+/// A rule of the `routes` list, a push that matches every given condition also goes to its devices
+#[derive(Deserialize, Clone, Debug)]
+pub struct Route {
+    #[serde(default)]
+    level: Option<Level>,
+
+    #[serde(default)]
+    group: Option<String>,
+
+    /// Tags the push must all have
+    #[serde(default)]
+    tags: Vec<String>,
+
+    /// Device names, group names or keys
+    devices: Vec<String>,
+}
+
+/// Devices the routes add to a push, and why
+#[derive(Default, Debug)]
+pub struct Routing {
+    pub devices: Vec<String>,
+    pub notes: Vec<String>,
+}
+
+impl Route {
+    /// The conditions as text, `None` if the push doesn't meet them
+    fn matches(&self, push: &Push, tags: &[String]) -> Option<String> {
+        let mut conditions = Vec::new();
+        if let Some(level) = self.level {
+            if push.level() != Some(level) {
+                return None;
+            }
+            conditions.push(format!("level {level:?}").to_lowercase());
+        }
+        if let Some(group) = &self.group {
+            if push.group() != Some(group.as_str()) {
+                return None;
+            }
+            conditions.push(format!("group {group}"));
+        }
+        for tag in &self.tags {
+            if !tags.contains(tag) {
+                return None;
+            }
+            conditions.push(format!("tag {tag}"));
+        }
+        if conditions.is_empty() {
+            conditions.push("every push".to_owned());
+        }
+        Some(conditions.join(", "))
+    }
+}
+
+impl Routing {
+    /// Add the routed devices that are not in `devices` yet
+    pub fn add_to(&self, devices: &mut Vec<String>) {
+        for device in &self.devices {
+            if !devices.contains(device) {
+                devices.push(device.clone());
+            }
+        }
+    }
+}
+
+/// Apply every route of the service to the push, only the command line tags pushes
+pub fn route(service: &Service, push: &Push, tags: &[String]) -> Routing {
+    let mut routing = Routing::default();
+    for route in service.routes() {
+        let Some(conditions) = route.matches(push, tags) else {
+            continue;
+        };
+        routing
+            .notes
+            .push(format!("{conditions} -> {}", route.devices.join(", ")));
+        for device in service.resolve(&route.devices) {
+            if !routing.devices.contains(&device) {
+                routing.devices.push(device);
+            }
+        }
+    }
+    routing
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn service() -> Service {
+        serde_json::from_value(json!({
+            "device_keys": ["D"],
+            "devices": { "phone": "P", "tablet": "T", "watch": "W" },
+            "groups": { "oncall": ["phone", "watch"] },
+            "routes": [
+                { "level": "critical", "devices": ["oncall"] },
+                { "group": "backup", "devices": ["tablet"] },
+                { "tags": ["db", "prod"], "devices": ["phone"] },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn push(fields: Value) -> Push {
+        serde_json::from_value(fields).unwrap()
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn every_matching_route_applies() {
+        let push = push(json!({ "body": "b", "level": "critical", "group": "backup" }));
+        let routing = route(&service(), &push, &tags(&["db", "prod"]));
+        assert_eq!(routing.devices, ["P", "W", "T"]);
+        assert_eq!(
+            routing.notes,
+            [
+                "level critical -> oncall",
+                "group backup -> tablet",
+                "tag db, tag prod -> phone",
+            ]
+        );
+    }
+
+    #[test]
+    fn conditions_must_all_hold() {
+        let service = service();
+        let routing = route(
+            &service,
+            &push(json!({ "body": "b", "level": "active" })),
+            &[],
+        );
+        assert!(routing.devices.is_empty());
+
+        let backup = push(json!({ "body": "b", "group": "backup" }));
+        assert_eq!(route(&service, &backup, &[]).devices, ["T"]);
+        assert_eq!(route(&service, &backup, &tags(&["db"])).devices, ["T"]);
+        assert_eq!(
+            route(&service, &backup, &tags(&["db", "prod", "eu"])).devices,
+            ["T", "P"]
+        );
+    }
+
+    #[test]
+    fn no_route_keeps_the_devices() {
+        let service = service();
+        let routing = route(&service, &push(json!({ "body": "b" })), &tags(&["web"]));
+        assert!(routing.devices.is_empty() && routing.notes.is_empty());
+
+        let mut devices = service.device_keys();
+        routing.add_to(&mut devices);
+        assert_eq!(devices, ["D"]);
+    }
+
+    #[test]
+    fn routed_devices_come_on_top() {
+        let service = service();
+        let routing = route(
+            &service,
+            &push(json!({ "body": "b", "level": "critical" })),
+            &[],
+        );
+        let mut devices = vec!["P".to_owned(), "D".to_owned()];
+        routing.add_to(&mut devices);
+        assert_eq!(devices, ["P", "D", "W"]);
+    }
+
+    #[test]
+    fn route_without_conditions() {
+        let service = serde_json::from_value::<Service>(json!({
+            "routes": [{ "devices": ["K"] }],
+        }))
+        .unwrap();
+        let routing = route(&service, &push(json!({ "body": "b" })), &[]);
+        assert_eq!(routing.devices, ["K"]);
+        assert_eq!(routing.notes, ["every push -> K"]);
+    }
+}
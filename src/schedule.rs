@@ -0,0 +1,440 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anstream::{eprintln, println};
+use anyhow::{Result, bail};
+use clap::{Args, Subcommand};
+use owo_colors::OwoColorize;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::bark::Service;
+use crate::output::print_results;
+use crate::send::Envelope;
+use crate::spool::Outbox;
+use crate::state::{create_json, fnv1a, lock, read_json, state_dir, try_lock, write_json};
+use crate::time::{format_local, now, parse_at, parse_duration};
+
+#[derive(Args, Debug)]
+pub struct When {
+    /// Deliver the push at a time, e.g. 18:00 or "2025-01-31 08:30"
+    #[arg(long, value_name = "TIME", value_parser = parse_at, conflicts_with = "after")]
+    at: Option<u64>,
+
+    /// Deliver the push after a while, e.g. 45m
+    #[arg(long = "in", value_name = "DURATION", value_parser = parse_duration)]
+    after: Option<Duration>,
+
+    /// Wait in the foreground until it is time, instead of leaving it to `barsk scheduler`
+    #[arg(long)]
+    pub wait: bool,
+}
+
+impl When {
+    /// When the push is due, in seconds since the unix epoch
+    pub fn due(&self) -> Option<u64> {
+        self.at
+            .or_else(|| self.after.map(|after| now() + after.as_secs()))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ScheduleCommand {
+    /// List pushes waiting for their time
+    List,
+
+    /// Cancel scheduled pushes
+    Cancel {
+        /// Ids shown by `barsk schedule list`
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+}
+
+/// A push waiting for its time, stored as one json file
+#[derive(Serialize, Deserialize, Debug)]
+struct Item {
+    id: String,
+    due: u64,
+    label: String,
+    envelope: Envelope,
+    #[serde(default)]
+    queue_on_failure: bool,
+    /// Deliveries that failed, the push stays in the schedule until `MAX_ATTEMPTS`
+    #[serde(default)]
+    attempts: u32,
+}
+
+/// Tries of a push that isn't queued in the outbox on failure
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before trying a failed push again
+const RETRY_AFTER: u64 = 60;
+
+fn dir() -> PathBuf {
+    state_dir().join("schedule")
+}
+
+fn item_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.json"))
+}
+
+/// Where an item is while it is delivered
+fn inflight_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.inflight"))
+}
+
+/// The items in `dir` by due time, unreadable ones are moved aside
+async fn items(dir: &Path) -> Result<Vec<Item>> {
+    let mut items = Vec::new();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(er) if er.kind() == ErrorKind::NotFound => return Ok(items),
+        Err(er) => return Err(er.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match read_json::<Item>(&path).await {
+            Ok(Some(item)) => items.push(item),
+            Ok(None) => {}
+            Err(er) => {
+                let aside = path.with_extension("unreadable");
+                eprintln!("{}: {}: {}", "unreadable".red(), path.display(), er);
+                eprintln!("{}: {}", "moved to".yellow(), aside.display());
+                fs::rename(&path, &aside).await?;
+            }
+        }
+    }
+    items.sort_by(|a, b| a.due.cmp(&b.due).then_with(|| a.id.cmp(&b.id)));
+    Ok(items)
+}
+
+/// Store a push to be delivered at `due`, return its id
+pub async fn add(
+    due: u64,
+    label: String,
+    envelope: &Envelope,
+    queue_on_failure: bool,
+) -> Result<String> {
+    create(&dir(), due, label, envelope, queue_on_failure).await
+}
+
+async fn create(
+    dir: &Path,
+    due: u64,
+    label: String,
+    envelope: &Envelope,
+    queue_on_failure: bool,
+) -> Result<String> {
+    // The same push may be added twice in a second, e.g. for devices on two servers
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let seed = format!("{}-{}-{n}-{}", now(), std::process::id(), envelope.payload);
+    let id = format!("{:016x}", fnv1a(seed.as_bytes()));
+    let item = Item {
+        id: id.clone(),
+        due,
+        label,
+        envelope: envelope.clone(),
+        queue_on_failure,
+        attempts: 0,
+    };
+    match create_json(&item_path(dir, &id), &item).await {
+        Ok(()) => Ok(id),
+        Err(er) if er.kind() == ErrorKind::AlreadyExists => {
+            bail!("A scheduled push with id {id} exists already")
+        }
+        Err(er) => Err(er.into()),
+    }
+}
+
+/// Store a push under a given id, replacing the push that had it
+pub async fn put(
+    id: &str,
+    due: u64,
+    label: String,
+    envelope: &Envelope,
+    queue_on_failure: bool,
+) -> Result<()> {
+    let item = Item {
+        id: id.to_owned(),
+        due,
+        label,
+        envelope: envelope.clone(),
+        queue_on_failure,
+        attempts: 0,
+    };
+    write_json(&item_path(&dir(), id), &item).await
+}
+
+pub async fn manage(command: &ScheduleCommand) -> Result<()> {
+    match command {
+        ScheduleCommand::List => {
+            for item in items(&dir()).await? {
+                println!(
+                    "{}  {}  {} device(s)  {}",
+                    item.id.cyan(),
+                    format_local(item.due),
+                    item.envelope.devices.len(),
+                    item.label
+                );
+            }
+        }
+        ScheduleCommand::Cancel { ids } => {
+            for id in ids {
+                match fs::remove_file(item_path(&dir(), id)).await {
+                    Ok(()) => println!("{}: {}", "cancelled".green(), id),
+                    Err(er) if er.kind() == ErrorKind::NotFound => {
+                        bail!("No scheduled push {id}")
+                    }
+                    Err(er) => return Err(er.into()),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Move an item out of the schedule while it is delivered, false if it was cancelled meanwhile
+async fn claim(dir: &Path, id: &str) -> Result<bool> {
+    match fs::rename(item_path(dir, id), inflight_path(dir, id)).await {
+        Ok(()) => Ok(true),
+        Err(er) if er.kind() == ErrorKind::NotFound => Ok(false),
+        Err(er) => Err(er.into()),
+    }
+}
+
+/// Put items back that were being delivered when a scheduler stopped, only the scheduler
+/// that holds the lock may do this
+async fn recover(dir: &Path) -> Result<()> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(er) if er.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(er) => return Err(er.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "inflight") {
+            eprintln!("{}: {}", "recovered".yellow(), path.display());
+            fs::rename(&path, path.with_extension("json")).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Items that are due at `now`, and when the next one is due
+fn due(items: Vec<Item>, now: u64) -> (Vec<Item>, Option<u64>) {
+    let next = items.iter().map(|item| item.due).find(|due| *due > now);
+    let due = items.into_iter().filter(|item| item.due <= now).collect();
+    (due, next)
+}
+
+/// Keep the devices that failed for another try, false once the item had `MAX_ATTEMPTS`
+fn retry(item: &mut Item, failed: Vec<String>, now: u64) -> bool {
+    item.attempts += 1;
+    if item.attempts >= MAX_ATTEMPTS {
+        return false;
+    }
+    item.envelope = item.envelope.only(failed);
+    item.due = now + RETRY_AFTER;
+    true
+}
+
+/// Deliver a claimed item, it stays in the schedule for the devices that failed
+async fn deliver(
+    client: &Client,
+    outbox: &Outbox,
+    service: &Service,
+    dir: &Path,
+    mut item: Item,
+) -> Result<()> {
+    println!("{}: {} {}", "delivering".cyan(), item.id, item.label);
+    item.envelope.resolve(service);
+    let results = item.envelope.deliver(client).await?;
+    print_results(&results);
+
+    let failed = results
+        .iter()
+        .filter(|d| d.is_retryable())
+        .map(|d| d.device.clone())
+        .collect::<Vec<_>>();
+    if item.queue_on_failure {
+        if let Some(path) = outbox.queue(&item.envelope, &results).await? {
+            eprintln!("{}: {}", "queued for retry".yellow(), path.display());
+        }
+    } else if !failed.is_empty() {
+        if retry(&mut item, failed, now()) {
+            eprintln!(
+                "{}: {} again in {RETRY_AFTER}s",
+                "retrying".yellow(),
+                item.id
+            );
+            write_json(&item_path(dir, &item.id), &item).await?;
+        } else {
+            eprintln!(
+                "{}: {} after {} attempts",
+                "dropped".red(),
+                item.id,
+                item.attempts
+            );
+        }
+    }
+    fs::remove_file(inflight_path(dir, &item.id)).await?;
+    Ok(())
+}
+
+/// Deliver scheduled pushes when they are due, forever. One scheduler runs at a time,
+/// others wait for it to stop and take over.
+pub async fn run(client: &Client, outbox: &Outbox, service: &Service) -> Result<()> {
+    let dir = dir();
+    let path = state_dir().join("scheduler");
+    let _lock = match try_lock(&path).await? {
+        Some(lock) => lock,
+        None => {
+            eprintln!("{}: another scheduler is running", "waiting".yellow());
+            lock(&path).await?
+        }
+    };
+    println!("Scheduler watching {}", dir.display());
+    recover(&dir).await?;
+    loop {
+        let now = now();
+        let (items, next) = due(items(&dir).await?, now);
+
+        for item in items {
+            if claim(&dir, &item.id).await? {
+                deliver(client, outbox, service, &dir, item).await?;
+            }
+        }
+
+        // Wake up now and then to notice pushes scheduled meanwhile
+        let wait = next.map_or(30, |due| due.saturating_sub(now).clamp(1, 30));
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+    }
+}
+
+/// Sleep in the foreground until `due`
+pub async fn wait_until(due: u64) {
+    let wait = due.saturating_sub(now());
+    if wait > 0 {
+        eprintln!("{}: {}", "waiting until".cyan(), format_local(due));
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::bark::Transport;
+
+    fn envelope(devices: &[&str]) -> Envelope {
+        Envelope {
+            server: "https://h".to_owned(),
+            devices: devices.iter().map(|d| d.to_string()).collect(),
+            payload: "{}".to_owned(),
+            encrypted: false,
+            backends: HashMap::new(),
+            push: None,
+            transport: Transport::Post,
+        }
+    }
+
+    fn item(id: &str, due: u64) -> Item {
+        Item {
+            id: id.to_owned(),
+            due,
+            label: id.to_owned(),
+            envelope: envelope(&["a"]),
+            queue_on_failure: false,
+            attempts: 0,
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("barsk-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn add_and_list() {
+        let dir = test_dir("schedule-list");
+        let late = create(&dir, 200, "late".into(), &envelope(&["a"]), false)
+            .await
+            .unwrap();
+        let early = create(&dir, 100, "early".into(), &envelope(&["a", "b"]), true)
+            .await
+            .unwrap();
+        assert_ne!(late, early);
+
+        let items = items(&dir).await.unwrap();
+        let listed = items
+            .iter()
+            .map(|item| (item.id.as_str(), item.due, item.label.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(listed, [(&*early, 100, "early"), (&*late, 200, "late")]);
+        assert!(items[0].queue_on_failure);
+        assert_eq!(items[0].envelope.devices, ["a", "b"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unreadable_item_moved_aside() {
+        let dir = test_dir("schedule-unreadable");
+        let id = create(&dir, 100, "ok".into(), &envelope(&["a"]), false)
+            .await
+            .unwrap();
+        std::fs::write(dir.join("broken.json"), "{\"id\": ").unwrap();
+
+        let items = items(&dir).await.unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id, id);
+        assert!(!dir.join("broken.json").exists());
+        assert!(dir.join("broken.unreadable").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn due_selection() {
+        let items = vec![
+            item("a", 100),
+            item("b", 150),
+            item("c", 200),
+            item("d", 300),
+        ];
+        let (due_now, next) = due(items, 150);
+        let ids = due_now
+            .iter()
+            .map(|item| item.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["a", "b"]);
+        assert_eq!(next, Some(200));
+
+        let (due_now, next) = due(vec![item("a", 100)], 50);
+        assert!(due_now.is_empty());
+        assert_eq!(next, Some(100));
+        assert_eq!(due(vec![item("a", 100)], 100).1, None);
+    }
+
+    #[test]
+    fn retry_until_max_attempts() {
+        let mut item = item("a", 100);
+        item.envelope = envelope(&["a", "b"]);
+
+        assert!(retry(&mut item, vec!["b".to_owned()], 1000));
+        assert_eq!(item.envelope.devices, ["b"]);
+        assert_eq!(item.due, 1000 + RETRY_AFTER);
+        for _ in 2..MAX_ATTEMPTS {
+            assert!(retry(&mut item, vec!["b".to_owned()], 1000));
+        }
+        assert!(!retry(&mut item, vec!["b".to_owned()], 1000));
+        assert_eq!(item.attempts, MAX_ATTEMPTS);
+    }
+}
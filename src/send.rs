@@ -0,0 +1,703 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anstream::eprintln;
+use anyhow::{Result, bail};
+use owo_colors::OwoColorize;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::{
+    Client, Response, StatusCode,
+    header::{self, HeaderMap, HeaderValue},
+};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Map, Value};
+use url::{Url, form_urlencoded};
+
+use crate::backend::{Backend, Notifier, post};
+use crate::bark::{Encryption, Push, Service, Transport};
+use crate::output::print_results;
+use crate::{quiet, route};
+
+/// Everything but unreserved characters is encoded in path segments, newlines and slashes too
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Longer GET urls are sent as POST, many proxies and servers cut urls at 4 or 8 KiB
+const MAX_URL_LENGTH: usize = 4000;
+
+/// A push that is ready to be sent: serialized and encrypted if required.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Envelope {
+    pub server: String,
+    pub devices: Vec<String>,
+    pub payload: String,
+    pub encrypted: bool,
+
+    /// Devices of other backends by name, looked up in the configuration so that their
+    /// credentials are never written to the state directory
+    #[serde(skip)]
+    pub backends: HashMap<String, Backend>,
+
+    /// The push as it is, only kept while devices of other backends need it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub push: Option<Push>,
+
+    #[serde(default)]
+    pub transport: Transport,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Resp {
+    pub code: u16,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+/// The outcome of sending a push to one device.
+#[derive(Serialize, Debug)]
+pub struct Delivery {
+    #[serde(serialize_with = "ser_hidden")]
+    pub device: String,
+    pub status: Option<u16>,
+    pub code: Option<u16>,
+    pub message: Option<String>,
+    pub timestamp: Option<u64>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+impl Delivery {
+    pub fn new(device: String) -> Self {
+        Self {
+            device,
+            status: None,
+            code: None,
+            message: None,
+            timestamp: None,
+            latency_ms: 0,
+            error: None,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+            && self
+                .code
+                .and_then(|code| StatusCode::from_u16(code).ok())
+                .is_some_and(|code| code.is_success())
+    }
+
+    /// Failures that may pass later: the server was unreachable or had an error
+    pub fn is_retryable(&self) -> bool {
+        !self.is_success()
+            && (self.error.is_some()
+                || self.status.is_some_and(|status| status >= 500)
+                || self.code.is_some_and(|code| code >= 500))
+    }
+}
+
+fn ser_hidden<S>(value: &str, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&crate::hide_str(value))
+}
+
+/// Byte offset of an ASCII `needle` in `text`, ignoring ASCII case
+fn find_ignore_case(text: &str, needle: &str) -> Option<usize> {
+    text.as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// The start of a body that is not a Bark reply, the title of an HTML page
+fn snippet(text: &str) -> String {
+    let title = find_ignore_case(text, "<title>")
+        .map(|start| start + "<title>".len())
+        .and_then(|start| Some((start, start + find_ignore_case(&text[start..], "</title>")?)))
+        .and_then(|(start, end)| text.get(start..end));
+    let text = title
+        .unwrap_or(text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut snippet = text.chars().take(120).collect::<String>();
+    if snippet.len() < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// The message of a JSON error that is not a Bark reply
+fn server_message(text: &str) -> Option<String> {
+    let Value::Object(fields) = serde_json::from_str::<Value>(text).ok()? else {
+        return None;
+    };
+    ["message", "error", "detail"]
+        .iter()
+        .find_map(|key| fields.get(*key)?.as_str().map(str::to_owned))
+}
+
+/// Read the reply of a Bark server. Anything else, like the error page of a proxy, is reported
+/// with its status, its message and the start of its body; a success status without a Bark
+/// reply is an error.
+async fn read_reply(resp: Response, delivery: &mut Delivery) -> Result<()> {
+    let status = resp.status();
+    let json = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("json"));
+    let text = resp.text().await?;
+
+    if json && let Ok(resp) = serde_json::from_str::<Resp>(&text) {
+        #[cfg(debug_assertions)]
+        println!("{:#?}", resp);
+
+        delivery.code = Some(resp.code);
+        delivery.message = Some(resp.message);
+        delivery.timestamp = resp.timestamp;
+        return Ok(());
+    }
+
+    let snippet = snippet(&text);
+    let detail = match server_message(&text) {
+        Some(message) if !snippet.is_empty() && snippet != message => {
+            format!("{message}, body: {snippet}")
+        }
+        Some(message) => message,
+        None => snippet,
+    };
+    let message = if detail.is_empty() || detail == status.to_string() {
+        format!("HTTP {status}")
+    } else {
+        format!("HTTP {status}: {detail}")
+    };
+    if status.is_success() {
+        bail!("Not a Bark reply, {message}");
+    }
+    delivery.code = Some(status.as_u16());
+    delivery.message = Some(message);
+    Ok(())
+}
+
+/// `path` under the url `base`, after its last segment whether or not it ends with a slash
+pub fn endpoint(base: &str, path: &str) -> Result<Url> {
+    let mut url = Url::parse(base)?;
+    if url.cannot_be_a_base() {
+        bail!("{base} can't have a path");
+    }
+    url.set_path(&format!("{}/{path}", url.path().trim_end_matches('/')));
+    Ok(url)
+}
+
+pub fn client() -> Result<Client> {
+    let client = Client::builder()
+        .default_headers({
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, HeaderValue::from_static("reqwest/0.12"));
+            headers
+        })
+        .build()?;
+    Ok(client)
+}
+
+/// The Bark server of the envelope, the device is a device key
+pub struct Bark;
+
+impl Notifier for Bark {
+    async fn notify(
+        &self,
+        client: &Client,
+        envelope: &Envelope,
+        device: &str,
+        delivery: &mut Delivery,
+    ) -> Result<()> {
+        // A push that has no url, or too long a url, goes by POST
+        let link = match envelope.transport {
+            Transport::Get => match envelope.url(device) {
+                Ok(link) if link.as_str().len() <= MAX_URL_LENGTH => Some(link),
+                Ok(_) => {
+                    eprintln!(
+                        "{}: the url for {} is too long, sent by POST",
+                        "warning".yellow(),
+                        crate::hide_str(device)
+                    );
+                    None
+                }
+                Err(er) => {
+                    eprintln!(
+                        "{}: no url for {}, sent by POST: {}",
+                        "warning".yellow(),
+                        crate::hide_str(device),
+                        er
+                    );
+                    None
+                }
+            },
+            Transport::Post => None,
+        };
+        let request = match link {
+            Some(link) => client.get(link),
+            None => client
+                .post(endpoint(&envelope.server, device)?)
+                .header(header::CONTENT_TYPE, envelope.content_type())
+                .body(envelope.body()),
+        };
+        let resp = post(request, delivery).await?;
+        read_reply(resp, delivery).await
+    }
+}
+
+impl Envelope {
+    /// Serialize and encrypt a push for the devices of the service
+    pub fn seal(
+        push: &Push,
+        service: &Service,
+        devices: Vec<String>,
+        encryption: &Encryption,
+    ) -> Result<Self> {
+        let payload = json5::to_string(push)?;
+
+        let payload = if encryption.encrypted() {
+            encryption.encrypt(&payload)?
+        } else {
+            payload
+        };
+
+        let mut envelope = Self {
+            server: service.server().to_owned(),
+            devices,
+            payload,
+            encrypted: encryption.encrypted(),
+            backends: HashMap::new(),
+            push: None,
+            transport: service.transport(),
+        };
+        envelope.resolve(service);
+        if !envelope.backends.is_empty() {
+            envelope.push = Some(push.clone());
+        }
+        Ok(envelope)
+    }
+
+    /// Look up the devices of other backends in the configuration, an envelope read from the
+    /// state directory has none
+    pub fn resolve(&mut self, service: &Service) {
+        self.backends = self
+            .devices
+            .iter()
+            .filter_map(|dev| Some((dev.clone(), service.backend(dev)?.resolved(service))))
+            .collect();
+    }
+
+    /// The envelope for some of its devices, without the push if none of them needs it
+    pub fn only(&self, devices: Vec<String>) -> Self {
+        let backends = self
+            .backends
+            .iter()
+            .filter(|(name, _)| devices.contains(name))
+            .map(|(name, backend)| (name.clone(), backend.clone()))
+            .collect::<HashMap<_, _>>();
+        Self {
+            push: self.push.clone().filter(|_| !backends.is_empty()),
+            backends,
+            devices,
+            ..self.clone()
+        }
+    }
+
+    /// The Bark url for one device key, `/:key/:title/:subtitle/:body?field=...`,
+    /// or `/:key?ciphertext=...` if it is encrypted
+    pub fn url(&self, device: &str) -> Result<Url> {
+        let mut url = Url::parse(&self.server)?;
+        if url.cannot_be_a_base() {
+            bail!("{} can't have a path", self.server);
+        }
+        let mut segments = vec![device.to_owned()];
+        let mut query = Vec::new();
+
+        if self.encrypted {
+            query.push(("ciphertext".to_owned(), self.payload.clone()));
+        } else {
+            let mut fields = serde_json::from_str::<Map<String, Value>>(&self.payload)?;
+            let Some(Value::String(body)) = fields.remove("body") else {
+                bail!("A Bark url needs a body");
+            };
+            // The subtitle only has a place in the path after the title
+            if let Some(Value::String(title)) = fields.remove("title") {
+                segments.push(title);
+                if let Some(Value::String(subtitle)) = fields.remove("subtitle") {
+                    segments.push(subtitle);
+                }
+            }
+            segments.push(body);
+
+            for (name, value) in fields {
+                let value = match value {
+                    Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                query.push((name, value));
+            }
+        }
+
+        let path = segments
+            .iter()
+            .map(|segment| utf8_percent_encode(segment, SEGMENT).to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+        url.set_path(&format!("{}/{path}", url.path().trim_end_matches('/')));
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        Ok(url)
+    }
+
+    fn content_type(&self) -> HeaderValue {
+        if self.encrypted {
+            HeaderValue::from_static("application/x-www-form-urlencoded")
+        } else {
+            HeaderValue::from_static("application/json; charset=utf-8")
+        }
+    }
+
+    fn body(&self) -> String {
+        if self.encrypted {
+            form_urlencoded::Serializer::new(String::new())
+                .append_pair("ciphertext", &self.payload)
+                .finish()
+        } else {
+            self.payload.clone()
+        }
+    }
+
+    /// Send to every device concurrently, one delivery per device in order.
+    pub async fn deliver(&self, client: &Client) -> Result<Vec<Delivery>> {
+        let envelope = Arc::new(self.clone());
+        let mut handlers = Vec::with_capacity(self.devices.len());
+        for dev in &self.devices {
+            let envelope = envelope.clone();
+            let client = client.clone();
+            let device = dev.clone();
+
+            let handle = tokio::spawn(async move {
+                let mut delivery = Delivery::new(device.clone());
+                let start = Instant::now();
+                let posted = match envelope.backends.get(&device) {
+                    Some(backend) => {
+                        backend
+                            .notify(&client, &envelope, &device, &mut delivery)
+                            .await
+                    }
+                    None => {
+                        Bark.notify(&client, &envelope, &device, &mut delivery)
+                            .await
+                    }
+                };
+                if let Err(er) = posted {
+                    delivery.error = Some(er.to_string());
+                }
+                delivery.latency_ms = start.elapsed().as_millis() as u64;
+                delivery
+            });
+            handlers.push(handle);
+        }
+
+        let mut deliveries = Vec::with_capacity(handlers.len());
+        for handle in handlers {
+            deliveries.push(handle.await?);
+        }
+        Ok(deliveries)
+    }
+}
+
+/// Sends pushes for the daemons, with the server, devices and encryption of the configuration
+pub struct Pusher {
+    service: Service,
+    encryption: Encryption,
+    client: Client,
+}
+
+impl Pusher {
+    pub fn new(service: Service, encryption: Encryption) -> Result<Self> {
+        Ok(Self {
+            service,
+            encryption,
+            client: client()?,
+        })
+    }
+
+    pub fn service(&self) -> &Service {
+        &self.service
+    }
+
+    /// Send to the named devices or groups, or the default devices if there are no names.
+    /// Results are printed, it fails if any device failed.
+    pub async fn push(&self, push: &Push, names: &[String]) -> Result<()> {
+        let results = self.deliver(push, names).await?;
+        print_results(&results);
+        check(&results)
+    }
+
+    /// Like `push`, but the results are returned instead of printed and checked
+    pub async fn deliver(&self, push: &Push, names: &[String]) -> Result<Vec<Delivery>> {
+        let mut devices = if names.is_empty() {
+            self.service.device_keys()
+        } else {
+            self.service.resolve(names)
+        };
+        let routing = route::route(&self.service, push, &[]);
+        for note in &routing.notes {
+            eprintln!("{}: {}", "routed".cyan(), note);
+        }
+        routing.add_to(&mut devices);
+        if devices.is_empty() {
+            bail!("No device to push to");
+        }
+
+        let quiet = quiet::plan(&self.service, push, devices);
+        for note in &quiet.notes {
+            eprintln!("{}: {}", "quiet hours".yellow(), note);
+        }
+        quiet
+            .defer(push, &self.service, &self.encryption, false)
+            .await?;
+
+        self.send(quiet.seal(push, &self.service, &self.encryption)?)
+            .await
+    }
+
+    /// Send to the named devices or groups only and right away, without routes and quiet hours
+    pub async fn push_now(&self, push: &Push, names: &[String]) -> Result<()> {
+        let devices = self.service.resolve(names);
+        if devices.is_empty() {
+            bail!("No device to push to");
+        }
+        let envelope = Envelope::seal(push, &self.service, devices, &self.encryption)?;
+        let results = self.send(vec![envelope]).await?;
+        print_results(&results);
+        check(&results)
+    }
+
+    async fn send(&self, envelopes: Vec<Envelope>) -> Result<Vec<Delivery>> {
+        let mut results = Vec::new();
+        for envelope in envelopes {
+            results.extend(envelope.deliver(&self.client).await?);
+        }
+        Ok(results)
+    }
+}
+
+/// Fail with the error of the first device that failed
+pub fn check(results: &[Delivery]) -> Result<()> {
+    if let Some(failed) = results.iter().find(|d| !d.is_success()) {
+        bail!(
+            "{}",
+            failed
+                .error
+                .clone()
+                .or_else(|| failed.message.clone())
+                .unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn envelope(server: &str, payload: Value) -> Envelope {
+        Envelope {
+            server: server.to_owned(),
+            devices: vec!["KEY".to_owned()],
+            payload: payload.to_string(),
+            encrypted: false,
+            backends: HashMap::new(),
+            push: None,
+            transport: Transport::Get,
+        }
+    }
+
+    fn url(server: &str, payload: Value) -> String {
+        envelope(server, payload).url("KEY").unwrap().to_string()
+    }
+
+    #[test]
+    fn fields_in_the_path() {
+        assert_eq!(url("https://h", json!({ "body": "b" })), "https://h/KEY/b");
+        assert_eq!(
+            url(
+                "https://h",
+                json!({ "title": "t", "subtitle": "s", "body": "b" })
+            ),
+            "https://h/KEY/t/s/b"
+        );
+        // Without a title the subtitle has no place in the path
+        assert_eq!(
+            url("https://h", json!({ "subtitle": "s", "body": "b" })),
+            "https://h/KEY/b?subtitle=s"
+        );
+    }
+
+    #[test]
+    fn segments_are_encoded() {
+        assert_eq!(
+            url("https://h", json!({ "title": "a/b", "body": "one\ntwo" })),
+            "https://h/KEY/a%2Fb/one%0Atwo"
+        );
+        assert_eq!(
+            url("https://h", json!({ "title": "?#%. ~_-", "body": "😀 é" })),
+            "https://h/KEY/%3F%23%25.%20~_-/%F0%9F%98%80%20%C3%A9"
+        );
+    }
+
+    #[test]
+    fn query_fields() {
+        assert_eq!(
+            url(
+                "https://h",
+                json!({ "body": "b", "url": "https://x/?a=1&b=2", "badge": 3, "group": "😀/" })
+            ),
+            "https://h/KEY/b?badge=3&group=%F0%9F%98%80%2F&url=https%3A%2F%2Fx%2F%3Fa%3D1%26b%3D2"
+        );
+    }
+
+    #[test]
+    fn server_path() {
+        assert_eq!(
+            url("https://h/bark", json!({ "body": "b" })),
+            "https://h/bark/KEY/b"
+        );
+        assert_eq!(
+            url("https://h/bark/", json!({ "body": "b" })),
+            "https://h/bark/KEY/b"
+        );
+    }
+
+    #[test]
+    fn encrypted() {
+        let mut envelope = envelope("https://h", json!({}));
+        envelope.encrypted = true;
+        envelope.payload = "a+b/c=".to_owned();
+        assert_eq!(
+            envelope.url("KEY").unwrap().as_str(),
+            "https://h/KEY?ciphertext=a%2Bb%2Fc%3D"
+        );
+    }
+
+    #[test]
+    fn no_url() {
+        assert!(
+            envelope("https://h", json!({ "title": "t" }))
+                .url("KEY")
+                .is_err()
+        );
+        assert!(
+            envelope("mailto:a@h", json!({ "body": "b" }))
+                .url("KEY")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn endpoints() {
+        let endpoint = |base, path| endpoint(base, path).unwrap().to_string();
+        assert_eq!(endpoint("https://h", "KEY"), "https://h/KEY");
+        assert_eq!(endpoint("https://h/bark", "KEY"), "https://h/bark/KEY");
+        assert_eq!(endpoint("https://h/bark/", "KEY"), "https://h/bark/KEY");
+        assert!(super::endpoint("mailto:a@h", "KEY").is_err());
+    }
+
+    async fn reply(status: u16, content_type: &str, body: &str) -> Result<Delivery> {
+        let resp = axum::http::Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body.to_owned())
+            .unwrap();
+        let mut delivery = Delivery::new("KEY".to_owned());
+        read_reply(Response::from(resp), &mut delivery).await?;
+        Ok(delivery)
+    }
+
+    #[tokio::test]
+    async fn bark_reply() {
+        let body = r#"{"code":200,"message":"success","timestamp":1}"#;
+        let delivery = reply(200, "application/json", body).await.unwrap();
+        assert!(delivery.is_success());
+        assert_eq!(delivery.message.as_deref(), Some("success"));
+    }
+
+    #[tokio::test]
+    async fn html_error_page() {
+        let page = "<html><HEAD><Title>502 Bad Gateway</TITLE></head>\
+                    <body><center><h1>502 Bad Gateway</h1></center></body></html>";
+        let delivery = reply(502, "text/html", page).await.unwrap();
+        assert_eq!(delivery.code, Some(502));
+        assert!(delivery.is_retryable());
+        assert_eq!(delivery.message.as_deref(), Some("HTTP 502 Bad Gateway"));
+
+        let page = "<html><title>İstanbul: service down</title></html>";
+        let delivery = reply(503, "text/html", page).await.unwrap();
+        assert_eq!(
+            delivery.message.as_deref(),
+            Some("HTTP 503 Service Unavailable: İstanbul: service down")
+        );
+    }
+
+    #[tokio::test]
+    async fn non_json_server_error() {
+        let delivery = reply(500, "text/plain", "upstream   timed\nout")
+            .await
+            .unwrap();
+        assert_eq!(
+            delivery.message.as_deref(),
+            Some("HTTP 500 Internal Server Error: upstream timed out")
+        );
+
+        let long = "x".repeat(500);
+        let delivery = reply(500, "text/plain", &long).await.unwrap();
+        assert!(
+            delivery
+                .message
+                .unwrap()
+                .ends_with(&format!("{}…", "x".repeat(120)))
+        );
+    }
+
+    #[tokio::test]
+    async fn json_error_with_message() {
+        let body = r#"{"error":"quota exceeded","retry":60}"#;
+        let delivery = reply(429, "application/json", body).await.unwrap();
+        assert_eq!(
+            delivery.message.as_deref(),
+            Some(
+                r#"HTTP 429 Too Many Requests: quota exceeded, body: {"error":"quota exceeded","retry":60}"#
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn success_without_bark_reply() {
+        let er = reply(200, "text/html", "<title>Login</title>")
+            .await
+            .unwrap_err();
+        assert_eq!(er.to_string(), "Not a Bark reply, HTTP 200 OK: Login");
+    }
+
+    #[test]
+    fn snippet_of_a_title() {
+        assert_eq!(snippet("<TITLE>Not Found</Title>"), "Not Found");
+        assert_eq!(snippet("İİİ <title>x</title>"), "x");
+        assert_eq!(snippet("<title>unclosed"), "<title>unclosed");
+    }
+}
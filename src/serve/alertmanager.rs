@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::{Relay, reply};
+use crate::bark::{Level, Push};
+use crate::state::fnv1a;
+
+/// The `alertmanager` section of the configuration file
+#[derive(Deserialize, Default, Debug)]
+pub struct AlertmanagerConfig {
+    /// Devices of alerts that match no route, the default devices if empty
+    #[serde(default)]
+    devices: Vec<String>,
+
+    /// Levels of the values of the severity label, e.g. `warning = "time-sensitive"`
+    #[serde(default)]
+    levels: HashMap<String, Level>,
+
+    /// Label that holds the severity, default is "severity"
+    #[serde(default)]
+    severity_label: Option<String>,
+
+    /// The first route whose labels all match picks the devices
+    #[serde(default)]
+    routes: Vec<Route>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Route {
+    #[serde(rename = "match", default)]
+    labels: HashMap<String, String>,
+
+    devices: Vec<String>,
+}
+
+/// Webhook payload of Alertmanager, version 4
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Notification {
+    #[serde(default)]
+    receiver: String,
+
+    #[serde(default)]
+    group_labels: BTreeMap<String, String>,
+
+    #[serde(default)]
+    alerts: Vec<Alert>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Alert {
+    status: String,
+
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+
+    #[serde(rename = "generatorURL", default)]
+    generator_url: Option<String>,
+
+    #[serde(default)]
+    fingerprint: Option<String>,
+}
+
+impl AlertmanagerConfig {
+    fn level(&self, alert: &Alert) -> Level {
+        if alert.status == "resolved" {
+            return Level::Passive;
+        }
+        let severity = alert
+            .labels
+            .get(self.severity_label.as_deref().unwrap_or("severity"))
+            .map(|s| s.to_lowercase())
+            .unwrap_or_default();
+        if let Some(level) = self.levels.get(&severity) {
+            return *level;
+        }
+        match severity.as_str() {
+            "critical" | "page" | "emergency" => Level::Critical,
+            "error" | "warning" => Level::TimeSensitive,
+            "info" | "none" => Level::Passive,
+            _ => Level::Active,
+        }
+    }
+
+    fn devices(&self, alert: &Alert) -> &[String] {
+        self.routes
+            .iter()
+            .find(|route| {
+                route
+                    .labels
+                    .iter()
+                    .all(|(name, value)| alert.labels.get(name) == Some(value))
+            })
+            .map_or(&self.devices, |route| &route.devices)
+    }
+}
+
+impl Alert {
+    fn push(&self, group: &str) -> serde_json::Result<Push> {
+        let name = self.labels.get("alertname").map_or("alert", String::as_str);
+        let body = match (
+            self.annotations.get("summary"),
+            self.annotations.get("description"),
+        ) {
+            (Some(summary), Some(description)) => format!("{summary}\n{description}"),
+            (Some(text), None) | (None, Some(text)) => text.clone(),
+            (None, None) => self
+                .labels
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        // Resolved replaces firing on the phone when both carry the same id
+        let id = self.fingerprint.clone().unwrap_or_else(|| {
+            let labels = serde_json::to_string(&self.labels).unwrap_or_default();
+            format!("{:016x}", fnv1a(labels.as_bytes()))
+        });
+
+        let mut fields = Map::new();
+        fields.insert(
+            "title".to_owned(),
+            format!("[{}] {name}", self.status.to_uppercase()).into(),
+        );
+        if let Some(instance) = self.labels.get("instance") {
+            fields.insert("subtitle".to_owned(), instance.clone().into());
+        }
+        fields.insert("body".to_owned(), body.into());
+        fields.insert("group".to_owned(), group.into());
+        fields.insert("id".to_owned(), id.into());
+        if let Some(url) = &self.generator_url {
+            fields.insert("url".to_owned(), url.clone().into());
+        }
+        serde_json::from_value(Value::Object(fields))
+    }
+}
+
+/// `POST /alertmanager`, one push per alert
+pub async fn receive(
+    State(relay): State<Arc<Relay>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let notification = match serde_json::from_slice::<Notification>(&body) {
+        Ok(notification) => notification,
+        Err(er) => return reply(StatusCode::BAD_REQUEST, er.to_string()),
+    };
+
+    let group = if notification.group_labels.is_empty() {
+        notification.receiver.clone()
+    } else {
+        notification
+            .group_labels
+            .values()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let config = &relay.alertmanager;
+    let mut failed = None;
+    for alert in &notification.alerts {
+        let mut push = match alert.push(&group) {
+            Ok(push) => push,
+            Err(er) => return reply(StatusCode::BAD_REQUEST, er.to_string()),
+        };
+        push.update_level(Some(config.level(alert)));
+
+        let response = relay
+            .dispatch(&headers, peer, config.devices(alert), &push)
+            .await;
+        if !response.status().is_success() && failed.is_none() {
+            failed = Some(response);
+        }
+    }
+
+    failed.unwrap_or_else(|| reply(StatusCode::OK, "success"))
+}
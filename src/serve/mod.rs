@@ -0,0 +1,516 @@
+mod alertmanager;
+mod webhook;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anstream::{eprintln, println};
+use anyhow::{Result, bail};
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::{ConnectInfo, OriginalUri, Path, RawQuery, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use clap::Args;
+use owo_colors::OwoColorize;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use tokio::net::TcpListener;
+
+use crate::bark::{Encryption, Push, Sections, Service};
+use crate::escalate;
+use crate::heartbeat::{self, HeartbeatConfig};
+use crate::quiet;
+use crate::route;
+use crate::send::{self, Pusher};
+use crate::spool::Outbox;
+use crate::time::now;
+
+pub use alertmanager::AlertmanagerConfig;
+pub use webhook::WebhookRule;
+
+static DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on, default is 127.0.0.1:8080
+    #[arg(long, value_name = "ADDR")]
+    listen: Option<SocketAddr>,
+
+    /// Token that clients send as "Authorization: Bearer <TOKEN>"
+    #[arg(long, env = "BARSK_SERVE_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Listen on other addresses than loopback without clients, so anyone may push
+    #[arg(long)]
+    open: bool,
+}
+
+/// The `serve` section of the configuration file
+#[derive(Deserialize, Default, Debug)]
+pub struct ServeConfig {
+    #[serde(default)]
+    listen: Option<SocketAddr>,
+
+    #[serde(default)]
+    clients: Vec<ClientRule>,
+}
+
+/// Who may push through the relay, and to which devices
+#[derive(Deserialize, Debug)]
+struct ClientRule {
+    #[serde(default)]
+    name: Option<String>,
+
+    /// Bearer token of the client, any token if missing
+    #[serde(default)]
+    token: Option<String>,
+
+    /// Device names, group names or keys the client may push to, any if empty
+    #[serde(default)]
+    devices: Vec<String>,
+
+    /// Addresses like 127.0.0.1 or 10.0.0.0/8 the client connects from, any if empty
+    #[serde(default)]
+    addresses: Vec<String>,
+}
+
+pub struct Relay {
+    service: Service,
+    encryption: Encryption,
+    clients: Vec<ClientRule>,
+    outbox: Option<Outbox>,
+    client: reqwest::Client,
+    alertmanager: AlertmanagerConfig,
+    webhooks: HashMap<String, Vec<WebhookRule>>,
+    heartbeats: Arc<HashMap<String, HeartbeatConfig>>,
+}
+
+fn reply(status: StatusCode, message: impl Into<String>) -> Response {
+    (
+        status,
+        Json(json!({
+            "code": status.as_u16(),
+            "message": message.into(),
+            "timestamp": now(),
+        })),
+    )
+        .into_response()
+}
+
+/// Whether `ip` is `pattern`, or in it when the pattern is a network like 10.0.0.0/8
+fn address_matches(pattern: &str, ip: IpAddr) -> bool {
+    let (addr, prefix) = match pattern.split_once('/') {
+        Some((addr, prefix)) => (addr, prefix.parse::<u32>().ok()),
+        None => (pattern, None),
+    };
+    let Ok(addr) = addr.trim().parse::<IpAddr>() else {
+        return false;
+    };
+    let ip = match (addr, ip) {
+        (IpAddr::V4(_), IpAddr::V6(v6)) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        _ => ip,
+    };
+    match (addr, ip) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let bits = prefix.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            u32::from(a) & mask == u32::from(b) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let bits = prefix.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            u128::from(a) & mask == u128::from(b) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Compare tokens in a time that doesn't depend on where they differ
+pub fn same_token(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Names in a field that may be one string, a comma separated string or a list
+fn names(value: Value) -> Vec<String> {
+    match value {
+        Value::String(s) => s
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        Value::Array(values) => values.into_iter().flat_map(names).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn form(bytes: &[u8], fields: &mut Map<String, Value>) {
+    for (key, value) in url::form_urlencoded::parse(bytes) {
+        fields.insert(key.into_owned(), Value::String(value.into_owned()));
+    }
+}
+
+impl Relay {
+    /// Whether the address of any client matches, for urls that can't carry a token
+    fn known_address(&self, ip: IpAddr) -> bool {
+        self.clients.is_empty()
+            || self.clients.iter().any(|client| {
+                client.addresses.is_empty()
+                    || client.addresses.iter().any(|p| address_matches(p, ip))
+            })
+    }
+
+    /// The devices a client may push to, `None` means any; an error is the rejection
+    fn authorize(
+        &self,
+        headers: &HeaderMap,
+        ip: IpAddr,
+    ) -> Result<Option<Vec<String>>, (StatusCode, &'static str)> {
+        if self.clients.is_empty() {
+            return Ok(None);
+        }
+
+        let token = bearer(headers);
+        let client = self.clients.iter().find(|client| {
+            client
+                .token
+                .as_deref()
+                .is_none_or(|t| token.is_some_and(|token| same_token(t, token)))
+                && (client.addresses.is_empty()
+                    || client.addresses.iter().any(|p| address_matches(p, ip)))
+        });
+
+        match client {
+            Some(client) if client.devices.is_empty() => Ok(None),
+            Some(client) => Ok(Some(self.service.resolve(&client.devices))),
+            None if token.is_none() => Err((StatusCode::UNAUTHORIZED, "missing token")),
+            None => Err((StatusCode::FORBIDDEN, "not allowed")),
+        }
+    }
+
+    /// Resolve, seal and send a push given by the fields of a request
+    async fn relay(
+        &self,
+        headers: &HeaderMap,
+        peer: SocketAddr,
+        mut fields: Map<String, Value>,
+    ) -> Response {
+        let mut requested = Vec::new();
+        for key in ["device_key", "device_keys", "devices"] {
+            requested.extend(fields.remove(key).map(names).unwrap_or_default());
+        }
+
+        let push = match serde_json::from_value::<Push>(Value::Object(fields)) {
+            Ok(push) if push.body().is_some() || push.title().is_some() => push,
+            Ok(_) => return reply(StatusCode::BAD_REQUEST, "push needs a body or a title"),
+            Err(er) => return reply(StatusCode::BAD_REQUEST, er.to_string()),
+        };
+
+        self.dispatch(headers, peer, &requested, &push).await
+    }
+
+    /// Send a push to the requested devices, or the configured ones, if the client may
+    async fn dispatch(
+        &self,
+        headers: &HeaderMap,
+        peer: SocketAddr,
+        requested: &[String],
+        push: &Push,
+    ) -> Response {
+        let allowed = match self.authorize(headers, peer.ip()) {
+            Ok(allowed) => allowed,
+            Err((status, message)) => return reply(status, message),
+        };
+
+        let mut devices = if requested.is_empty() {
+            self.service.device_keys()
+        } else {
+            self.service.resolve(requested)
+        };
+        if devices.is_empty() {
+            return reply(StatusCode::BAD_REQUEST, "no device to push to");
+        }
+        if let Some(allowed) = allowed
+            && let Some(denied) = devices.iter().find(|dev| !allowed.contains(dev))
+        {
+            return reply(
+                StatusCode::FORBIDDEN,
+                format!("not allowed to push to {}", crate::hide_str(denied)),
+            );
+        }
+
+        let routing = route::route(&self.service, push, &[]);
+        for note in &routing.notes {
+            eprintln!("{}: {}", "routed".cyan(), note);
+        }
+        routing.add_to(&mut devices);
+        self.send(push, devices).await
+    }
+
+    /// Seal and send a push that is already resolved
+    async fn send(&self, push: &Push, devices: Vec<String>) -> Response {
+        let quiet = quiet::plan(&self.service, push, devices);
+        for note in &quiet.notes {
+            eprintln!("{}: {}", "quiet hours".yellow(), note);
+        }
+        let deferred = quiet
+            .defer(push, &self.service, &self.encryption, self.outbox.is_some())
+            .await;
+        let envelopes =
+            match deferred.and_then(|_| quiet.seal(push, &self.service, &self.encryption)) {
+                Ok(envelopes) => envelopes,
+                Err(er) => return reply(StatusCode::INTERNAL_SERVER_ERROR, er.to_string()),
+            };
+
+        let mut results = Vec::new();
+        for envelope in &envelopes {
+            let delivered = match envelope.deliver(&self.client).await {
+                Ok(delivered) => delivered,
+                Err(er) => return reply(StatusCode::INTERNAL_SERVER_ERROR, er.to_string()),
+            };
+            if let Some(outbox) = &self.outbox {
+                match outbox.queue(envelope, &delivered).await {
+                    Ok(Some(path)) => {
+                        eprintln!("{}: {}", "queued for retry".yellow(), path.display())
+                    }
+                    Ok(None) => {}
+                    Err(er) => eprintln!("{}: {}", "error in queueing push".red(), er),
+                }
+            }
+            results.extend(delivered);
+        }
+
+        match results.iter().find(|d| !d.is_success()) {
+            None => {
+                println!(
+                    "{}: {} to {} device(s)",
+                    "relayed".green(),
+                    push.label(),
+                    results.len()
+                );
+                reply(StatusCode::OK, "success")
+            }
+            Some(failed) => {
+                let message = failed
+                    .error
+                    .clone()
+                    .or_else(|| failed.message.clone())
+                    .unwrap_or_default();
+                eprintln!("{}: {}: {}", "relay failed".red(), push.label(), message);
+                reply(StatusCode::BAD_GATEWAY, message)
+            }
+        }
+    }
+}
+
+async fn ping() -> Response {
+    reply(StatusCode::OK, "pong")
+}
+
+/// `GET` or `POST /heartbeat/{name}`, a ping of a configured heartbeat
+async fn heartbeat(
+    State(relay): State<Arc<Relay>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err((status, message)) = relay.authorize(&headers, peer.ip()) {
+        return reply(status, message);
+    }
+    if !relay.heartbeats.contains_key(&name) {
+        return reply(StatusCode::NOT_FOUND, "unknown heartbeat");
+    }
+    match heartbeat::ping(&name).await {
+        Ok(()) => reply(StatusCode::OK, "success"),
+        Err(er) => reply(StatusCode::INTERNAL_SERVER_ERROR, er.to_string()),
+    }
+}
+
+/// `GET /ack/{id}/{secret}`, the url of escalating pushes. It takes no token since a phone
+/// opens it, the secret of the escalation stands in for it.
+async fn ack(
+    State(relay): State<Arc<Relay>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path((id, secret)): Path<(String, String)>,
+) -> Response {
+    if !relay.known_address(peer.ip()) {
+        return reply(StatusCode::FORBIDDEN, "not allowed");
+    }
+    match escalate::ack_url(&id, &secret).await {
+        Ok(()) => {
+            println!("{}: {}", "acknowledged".green(), id);
+            reply(StatusCode::OK, "acknowledged")
+        }
+        Err(er) => reply(StatusCode::NOT_FOUND, er.to_string()),
+    }
+}
+
+/// `POST /push` and `POST /api/send` with a json body
+async fn push_json(
+    State(relay): State<Arc<Relay>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    match serde_json::from_slice::<Map<String, Value>>(&body) {
+        Ok(fields) => relay.relay(&headers, peer, fields).await,
+        Err(er) => reply(StatusCode::BAD_REQUEST, er.to_string()),
+    }
+}
+
+/// Bark style `/:key`, `/:key/:body`, `/:key/:title/:body` and `/:key/:title/:subtitle/:body`,
+/// with more fields in the query or in a form or json body
+async fn push_path(
+    State(relay): State<Arc<Relay>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let segments = uri
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    let mut fields = Map::new();
+    let names: &[&str] = match segments.len() {
+        1 => &["device_key"],
+        2 => &["device_key", "body"],
+        3 => &["device_key", "title", "body"],
+        4 => &["device_key", "title", "subtitle", "body"],
+        _ => return reply(StatusCode::NOT_FOUND, "not found"),
+    };
+    for (name, value) in names.iter().zip(segments) {
+        fields.insert(name.to_string(), Value::String(value));
+    }
+
+    if let Some(query) = query {
+        form(query.as_bytes(), &mut fields);
+    }
+    if !body.is_empty() {
+        let json = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/json"));
+        if json {
+            match serde_json::from_slice::<Map<String, Value>>(&body) {
+                Ok(more) => fields.extend(more),
+                Err(er) => return reply(StatusCode::BAD_REQUEST, er.to_string()),
+            }
+        } else {
+            form(&body, &mut fields);
+        }
+    }
+
+    relay.relay(&headers, peer, fields).await
+}
+
+pub async fn serve(
+    args: &ServeArgs,
+    sections: Sections,
+    service: Service,
+    encryption: Encryption,
+    outbox: Option<Outbox>,
+) -> Result<()> {
+    let config = sections.serve;
+    let mut clients = config.clients;
+    if let Some(token) = &args.token {
+        clients.push(ClientRule {
+            name: Some("--token".to_owned()),
+            token: Some(token.clone()),
+            devices: Vec::new(),
+            addresses: Vec::new(),
+        });
+    }
+    for client in &clients {
+        if client.token.is_none() && client.addresses.is_empty() {
+            eprintln!(
+                "{}: client {} has neither token nor addresses, anyone may push",
+                "warning".yellow(),
+                client.name.as_deref().unwrap_or("without name")
+            );
+        }
+    }
+
+    let listen = match args.listen.or(config.listen) {
+        Some(listen) => listen,
+        None => DEFAULT_LISTEN.parse()?,
+    };
+    if clients.is_empty() && !listen.ip().is_loopback() {
+        if !args.open {
+            bail!(
+                "Refusing to listen on {listen} without clients, anyone could push; \
+                 configure serve.clients, pass --token, or --open if that is intended"
+            );
+        }
+        eprintln!(
+            "{}: listening on {listen} without clients, anyone who can connect may push",
+            "warning".yellow().bold()
+        );
+    }
+
+    let heartbeats = Arc::new(sections.heartbeats);
+    if !heartbeats.is_empty() {
+        let pusher = Pusher::new(service.clone(), encryption.clone())?;
+        let heartbeats = heartbeats.clone();
+        tokio::spawn(async move {
+            if let Err(er) = heartbeat::watch(&heartbeats, &pusher).await {
+                eprintln!("{}: {}", "heartbeat watch stopped".red(), er);
+            }
+        });
+    }
+
+    let relay = Arc::new(Relay {
+        service,
+        encryption,
+        clients,
+        outbox,
+        client: send::client()?,
+        alertmanager: sections.alertmanager,
+        webhooks: sections.webhooks,
+        heartbeats,
+    });
+
+    let app = Router::new()
+        .route("/ping", get(ping))
+        .route("/push", post(push_json))
+        .route("/api/send", post(push_json))
+        .route("/alertmanager", post(alertmanager::receive))
+        .route("/hooks/{name}", post(webhook::receive))
+        .route("/heartbeat/{name}", get(heartbeat).post(heartbeat))
+        .route("/ack/{id}/{secret}", get(ack).post(ack))
+        .route("/{*path}", get(push_path).post(push_path))
+        .with_state(relay);
+
+    let listener = TcpListener::bind(listen).await?;
+    println!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,218 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{Relay, reply};
+use crate::bark::Push;
+use crate::template::PushTemplate;
+
+/// One rule of the `webhooks` section, served at `/hooks/<name>`
+#[derive(Deserialize, Debug)]
+pub struct WebhookRule {
+    /// Conditions that must all hold, e.g. `$.action == "opened"` or `$.pull_request.merged`
+    #[serde(default)]
+    when: Vec<String>,
+
+    /// Templates of push fields, e.g. `body = "{{ $.pull_request.title }}"`
+    #[serde(flatten)]
+    template: PushTemplate,
+
+    /// Devices of the push, the default devices if empty
+    #[serde(default)]
+    devices: Vec<String>,
+}
+
+/// Find the value at a path like `$.alerts[0].labels.name` or `$["key with spaces"]`
+pub fn json_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut rest = path.trim();
+    rest = rest.strip_prefix('$').unwrap_or(rest);
+    let mut value = value;
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            rest = after;
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']')?;
+            let key = after[..end].trim();
+            value = match key.parse::<usize>() {
+                Ok(index) => value.get(index)?,
+                Err(_) => value.get(key.trim_matches(|c| c == '"' || c == '\''))?,
+            };
+            rest = &after[end + 1..];
+            continue;
+        }
+        let end = rest.find(['.', '[']).unwrap_or(rest.len());
+        value = value.get(&rest[..end])?;
+        rest = &rest[end..];
+    }
+
+    Some(value)
+}
+
+/// Text of a value as it appears in templates, strings without quotes
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(a)) => !a.is_empty(),
+        Some(Value::Object(o)) => !o.is_empty(),
+    }
+}
+
+/// Split a condition at its first `==` or `!=` outside of quotes, into the path, whether the
+/// operator is `==` and the literal
+fn split_operator(condition: &str) -> Option<(&str, bool, &str)> {
+    let bytes = condition.as_bytes();
+    let mut quote = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            (Some(_), b'\\') => i += 1,
+            (Some(q), b) if b == q => quote = None,
+            (None, b @ (b'"' | b'\'')) => quote = Some(b),
+            (None, b @ (b'=' | b'!')) if bytes.get(i + 1) == Some(&b'=') => {
+                return Some((&condition[..i], b == b'=', &condition[i + 2..]));
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Check a condition: `path`, `!path`, `path == literal` or `path != literal`
+fn holds(condition: &str, payload: &Value) -> bool {
+    let condition = condition.trim();
+    if let Some((path, equal, literal)) = split_operator(condition) {
+        let literal = literal.trim();
+        let expected = serde_json::from_str::<Value>(literal)
+            .unwrap_or_else(|_| Value::String(literal.to_owned()));
+        let actual = json_path(payload, path).cloned().unwrap_or(Value::Null);
+        let same = actual == expected || text(&actual) == text(&expected);
+        return same == equal;
+    }
+    match condition.strip_prefix('!') {
+        Some(path) => !truthy(json_path(payload, path)),
+        None => truthy(json_path(payload, condition)),
+    }
+}
+
+impl WebhookRule {
+    fn matches(&self, payload: &Value) -> bool {
+        self.when.iter().all(|condition| holds(condition, payload))
+    }
+
+    fn push(&self, payload: &Value) -> anyhow::Result<Push> {
+        self.template
+            .render(|path| json_path(payload, path).map(text))
+    }
+}
+
+/// `POST /hooks/<name>`, one push per matching rule
+pub async fn receive(
+    State(relay): State<Arc<Relay>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some(rules) = relay.webhooks.get(&name) else {
+        return reply(StatusCode::NOT_FOUND, format!("no webhook {name}"));
+    };
+    let payload = match serde_json::from_slice::<Value>(&body) {
+        Ok(payload) => payload,
+        Err(er) => return reply(StatusCode::BAD_REQUEST, er.to_string()),
+    };
+
+    // A failing rule doesn't stop the others, the reply lists every failure
+    let mut sent = 0;
+    let mut status = None;
+    let mut failures = Vec::new();
+    for (index, rule) in rules.iter().enumerate() {
+        if !rule.matches(&payload) {
+            continue;
+        }
+        let response = match rule.push(&payload) {
+            Ok(push) => relay.dispatch(&headers, peer, &rule.devices, &push).await,
+            Err(er) => reply(StatusCode::BAD_REQUEST, er.to_string()),
+        };
+        if response.status().is_success() {
+            sent += 1;
+            continue;
+        }
+        status.get_or_insert(response.status());
+        failures.push(format!("rule {}: {}", index + 1, message(response).await));
+    }
+
+    match status {
+        Some(status) => reply(
+            status,
+            format!(
+                "{sent} sent, {} failed: {}",
+                failures.len(),
+                failures.join("; ")
+            ),
+        ),
+        None if sent == 0 => reply(StatusCode::OK, "ignored"),
+        None => reply(StatusCode::OK, "success"),
+    }
+}
+
+/// The message of a reply
+async fn message(response: Response) -> String {
+    let body = axum::body::to_bytes(response.into_body(), 64 * 1024)
+        .await
+        .unwrap_or_default();
+    match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Object(fields)) => fields.get("message").map(text).unwrap_or_default(),
+        _ => String::from_utf8_lossy(&body).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn operators_outside_quotes() {
+        let payload = json!({ "a": "x==y", "b": "c", "k==v": 1 });
+        assert!(holds(r#"$.a == "x==y""#, &payload));
+        assert!(!holds(r#"$.a != "x==y""#, &payload));
+        assert!(holds(r#"$.b != "x==y""#, &payload));
+        assert!(holds(r#"$["k==v"] == 1"#, &payload));
+        assert!(holds("!$.missing", &payload));
+        assert!(holds("$.b", &payload));
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(split_operator("$.a != 'b'"), Some(("$.a ", false, " 'b'")));
+        assert_eq!(
+            split_operator(r#"$.a == "q\"==""#),
+            Some(("$.a ", true, r#" "q\"==""#))
+        );
+        assert_eq!(split_operator("!$.a"), None);
+    }
+}
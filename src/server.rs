@@ -0,0 +1,275 @@
+use std::time::{Duration, Instant};
+
+use anstream::println;
+use anyhow::{Result, bail};
+use clap::Subcommand;
+use jiff::{Timestamp, civil::DateTime, tz::TimeZone};
+use owo_colors::OwoColorize;
+use reqwest::{Client, tls::TlsInfo};
+use serde_json::Value;
+
+use crate::bark::Service;
+use crate::send::endpoint;
+use crate::time::{format_local, now};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Certificates that expire sooner are shown as a warning
+const EXPIRY_WARNING: u64 = 14 * 24 * 3600;
+
+#[derive(Subcommand, Debug)]
+pub enum ServerCommand {
+    /// Query /ping, /healthz and /info of Bark servers, fails if any of them fails
+    Check {
+        /// Servers to check, default is the configured server
+        servers: Vec<String>,
+    },
+}
+
+/// One DER element: its tag, its content and what follows it
+fn element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n]
+            .iter()
+            .fold(0usize, |len, b| len << 8 | *b as usize);
+        rest = &rest[n..];
+        len
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// When an X.509 certificate expires, the `notAfter` of its validity
+fn not_after(der: &[u8]) -> Option<Timestamp> {
+    let (_, certificate, _) = element(der)?;
+    let (_, tbs, _) = element(certificate)?;
+
+    let mut fields = Vec::new();
+    let mut rest = tbs;
+    while fields.len() < 5
+        && let Some((tag, content, next)) = element(rest)
+    {
+        fields.push((tag, content));
+        rest = next;
+    }
+    // The version is optional, then come the serial, the signature, the issuer and the validity
+    let skip = usize::from(fields.first()?.0 == 0xa0);
+    let (_, validity) = fields.get(skip + 3)?;
+    let (_, _, after_not_before) = element(validity)?;
+    let (tag, time, _) = element(after_not_before)?;
+
+    let time = std::str::from_utf8(time).ok()?.trim_end_matches('Z');
+    let time = match tag {
+        // UTCTime, two digit years from 1950
+        0x17 => {
+            let century = if time.get(..2)?.parse::<u8>().ok()? < 50 {
+                "20"
+            } else {
+                "19"
+            };
+            format!("{century}{time}")
+        }
+        // GeneralizedTime
+        0x18 => time.to_owned(),
+        _ => return None,
+    };
+    let time = DateTime::strptime("%Y%m%d%H%M%S", &time).ok()?;
+    time.to_zoned(TimeZone::UTC)
+        .ok()
+        .map(|time| time.timestamp())
+}
+
+/// What a response says, in one line
+fn describe(path: &str, text: &str) -> String {
+    let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(text) else {
+        return text.trim().chars().take(60).collect();
+    };
+    if path != "info" {
+        return fields
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned();
+    }
+    ["version", "build", "arch", "commit", "devices"]
+        .iter()
+        .filter_map(|key| {
+            let value = fields.get(*key)?;
+            let value = value
+                .as_str()
+                .map_or_else(|| value.to_string(), str::to_owned);
+            Some(format!("{key} {value}"))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Check one server, print what was found and return whether it is healthy
+async fn check_server(client: &Client, server: &str) -> bool {
+    println!("{}", server.cyan().italic());
+    if let Err(er) = url::Url::parse(server) {
+        println!("  {}: {}", "invalid url".red(), er);
+        return false;
+    }
+
+    let mut healthy = true;
+    let mut expires = None;
+    for path in ["ping", "healthz", "info"] {
+        let label = format!("{path:<8}");
+        let Ok(url) = endpoint(server, path) else {
+            continue;
+        };
+        let start = Instant::now();
+        let resp = match client
+            .get(url)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+        {
+            Ok(resp) => resp,
+            Err(er) => {
+                healthy = false;
+                println!("  {label} {} {:#}", "failed".red(), anyhow::Error::from(er));
+                continue;
+            }
+        };
+        let latency = start.elapsed().as_millis();
+        if expires.is_none() {
+            expires = resp
+                .extensions()
+                .get::<TlsInfo>()
+                .and_then(|info| info.peer_certificate())
+                .and_then(not_after);
+        }
+        let text = resp.text().await.unwrap_or_default();
+        println!(
+            "  {label} {} {latency} ms  {}",
+            "ok".green(),
+            describe(path, &text)
+        );
+    }
+
+    if let Some(expires) = expires {
+        let expires = expires.as_second().max(0) as u64;
+        let left = expires.saturating_sub(now());
+        let line = format!(
+            "certificate expires {} ({} days)",
+            format_local(expires),
+            left / (24 * 3600)
+        );
+        if left == 0 {
+            healthy = false;
+            println!("  {:<8} {}", "tls", "certificate expired".red());
+        } else if left < EXPIRY_WARNING {
+            println!("  {:<8} {}", "tls", line.yellow());
+        } else {
+            println!("  {:<8} {}", "tls", line);
+        }
+    }
+    healthy
+}
+
+pub async fn manage(command: &ServerCommand, service: &Service) -> Result<()> {
+    match command {
+        ServerCommand::Check { servers } => {
+            let servers = if servers.is_empty() {
+                vec![service.server().to_owned()]
+            } else {
+                servers.clone()
+            };
+            let client = Client::builder().tls_info(true).timeout(TIMEOUT).build()?;
+
+            let mut failed = 0;
+            for server in &servers {
+                if !check_server(&client, server).await {
+                    failed += 1;
+                }
+            }
+            if failed > 0 {
+                bail!("{failed} of {} server(s) failed the check", servers.len());
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+
+    use super::*;
+
+    /// Version 3, valid until 2030-06-15 12:00:00 as UTCTime
+    const V3_UTC: &str = concat!(
+        "MIIBcjCCARegAwIBAgIUHJYhWJC7mU+oqy/V1zBiS9p0EJIwCgYIKoZIzj0EAwIwDjEMMAoGA1UEAwwDdXRjMB4X",
+        "DTI1MDEwMTAwMDAwMFoXDTMwMDYxNTEyMDAwMFowDjEMMAoGA1UEAwwDdXRjMFkwEwYHKoZIzj0CAQYIKoZIzj0D",
+        "AQcDQgAE6JfhhZUs9TEP13Ph57wRwqchLFteQpTMhph3SQg+1rtfmAd+7emQPW9UL+MYaa1cj4YMw8eh1KmrUQzI",
+        "7Z/KEKNTMFEwHQYDVR0OBBYEFAfRVS6XW3P1CZ+F3Y8z5D04YuIVMB8GA1UdIwQYMBaAFAfRVS6XW3P1CZ+F3Y8z",
+        "5D04YuIVMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSQAwRgIhALoFxXmfd+XJxlklmVqyskrOfrzC6040",
+        "EOcZhonKcvx6AiEAhe8VTUIRt5rNiURhjMyTsnulU3ks8i5YEp3UOVvP2d4=",
+    );
+
+    /// Version 3, valid until 2061-03-01 09:30:00 as GeneralizedTime
+    const V3_GENERALIZED: &str = concat!(
+        "MIIBcjCCARmgAwIBAgIUWGUSQ7dUHU60nlxGywxr/antQlEwCgYIKoZIzj0EAwIwDjEMMAoGA1UEAwwDZ2VuMCAX",
+        "DTI1MDEwMTAwMDAwMFoYDzIwNjEwMzAxMDkzMDAwWjAOMQwwCgYDVQQDDANnZW4wWTATBgcqhkjOPQIBBggqhkjO",
+        "PQMBBwNCAATol+GFlSz1MQ/Xc+HnvBHCpyEsW15ClMyGmHdJCD7Wu1+YB37t6ZA9b1Qv4xhprVyPhgzDx6HUqatR",
+        "DMjtn8oQo1MwUTAdBgNVHQ4EFgQUB9FVLpdbc/UJn4XdjzPkPThi4hUwHwYDVR0jBBgwFoAUB9FVLpdbc/UJn4Xd",
+        "jzPkPThi4hUwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNHADBEAiB8UFHcP8bikBL2MrEtFRZ34BngJXp6",
+        "vIloF4UnPydijwIgSDBu+BT8mmLS/9XQdhDvSzQSzZBDjPeh86KnIjeOeOs=",
+    );
+
+    /// Version 1 without the version field, valid until 2029-12-31 23:59:59
+    const V1_UTC: &str = concat!(
+        "MIIBFTCBuwIUWg6rasidoJT1JG3R2VZaD/zd9HYwCgYIKoZIzj0EAwIwDTELMAkGA1UEAwwCdjEwHhcNMjUwMTAx",
+        "MDAwMDAwWhcNMjkxMjMxMjM1OTU5WjANMQswCQYDVQQDDAJ2MTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABOiX",
+        "4YWVLPUxD9dz4ee8EcKnISxbXkKUzIaYd0kIPta7X5gHfu3pkD1vVC/jGGmtXI+GDMPHodSpq1EMyO2fyhAwCgYI",
+        "KoZIzj0EAwIDSQAwRgIhAISTGBsa1B18A8KLTXItMxBvEbSAlWa1FptZULmWk/MuAiEArLsTMUZRrCoSQxLYK/CB",
+        "7oDV2Ck338QszlBpiw2MeyQ=",
+    );
+
+    /// Version 1 without the version field, valid until 2061-12-31 23:59:59
+    const V1_GENERALIZED: &str = concat!(
+        "MIIBFzCBvQIUBFteXet5Eneyo9CZN3Lhrfvjoo4wCgYIKoZIzj0EAwIwDTELMAkGA1UEAwwCdjEwIBcNMjUwMTAx",
+        "MDAwMDAwWhgPMjA2MTEyMzEyMzU5NTlaMA0xCzAJBgNVBAMMAnYxMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE",
+        "6JfhhZUs9TEP13Ph57wRwqchLFteQpTMhph3SQg+1rtfmAd+7emQPW9UL+MYaa1cj4YMw8eh1KmrUQzI7Z/KEDAK",
+        "BggqhkjOPQQDAgNJADBGAiEAm9xs2jELp2crif9K9f+7WopP2LU+U5TsAhK8wO1/Sh0CIQDtWfJ2DPOxnoNU32UY",
+        "ydXDf77eGL+1+OvTFRydlmdEVQ==",
+    );
+
+    fn expiry(certificate: &str) -> Option<String> {
+        not_after(&STANDARD.decode(certificate).unwrap()).map(|time| time.to_string())
+    }
+
+    #[test]
+    fn with_version() {
+        assert_eq!(expiry(V3_UTC).as_deref(), Some("2030-06-15T12:00:00Z"));
+        assert_eq!(
+            expiry(V3_GENERALIZED).as_deref(),
+            Some("2061-03-01T09:30:00Z")
+        );
+    }
+
+    #[test]
+    fn without_version() {
+        assert_eq!(expiry(V1_UTC).as_deref(), Some("2029-12-31T23:59:59Z"));
+        assert_eq!(
+            expiry(V1_GENERALIZED).as_deref(),
+            Some("2061-12-31T23:59:59Z")
+        );
+    }
+
+    #[test]
+    fn broken_certificates() {
+        let der = STANDARD.decode(V3_UTC).unwrap();
+        assert_eq!(not_after(&der[..100]), None);
+        assert_eq!(not_after(&[]), None);
+        assert_eq!(not_after(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]), None);
+    }
+}
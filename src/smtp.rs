@@ -0,0 +1,716 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anstream::{eprintln, println};
+use anyhow::{Result, bail};
+use base64::prelude::*;
+use clap::Args;
+use owo_colors::OwoColorize;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use crate::bark::{Encryption, Level, Push, Service};
+use crate::send::Pusher;
+use crate::serve::same_token;
+
+static DEFAULT_LISTEN: &str = "127.0.0.1:2525";
+const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+const IDLE: Duration = Duration::from_secs(300);
+/// Longest command line, generous for AUTH with long credentials
+const MAX_LINE: usize = 4096;
+/// Deepest multipart nesting that is read, deeper parts are left out
+const MAX_DEPTH: usize = 8;
+
+#[derive(Args, Debug)]
+pub struct SmtpArgs {
+    /// Address to listen on, default is 127.0.0.1:2525
+    #[arg(long, value_name = "ADDR")]
+    listen: Option<SocketAddr>,
+
+    /// Largest message accepted in bytes, default is 1048576
+    #[arg(long, value_name = "BYTES")]
+    max_size: Option<usize>,
+}
+
+/// The `smtp` section of the configuration file
+#[derive(Deserialize, Default, Debug)]
+pub struct SmtpConfig {
+    #[serde(default)]
+    listen: Option<SocketAddr>,
+
+    #[serde(default)]
+    max_size: Option<usize>,
+
+    /// Users and passwords, senders must AUTH if not empty
+    #[serde(default)]
+    users: HashMap<String, String>,
+
+    /// Devices of recipients that are neither device names nor group names, rejected if empty
+    #[serde(default)]
+    devices: Vec<String>,
+}
+
+struct Gateway {
+    pusher: Pusher,
+    users: HashMap<String, String>,
+    devices: Vec<String>,
+    max_size: usize,
+}
+
+pub async fn serve(
+    args: &SmtpArgs,
+    config: SmtpConfig,
+    service: Service,
+    encryption: Encryption,
+) -> Result<()> {
+    let gateway = Arc::new(Gateway {
+        pusher: Pusher::new(service, encryption)?,
+        users: config.users,
+        devices: config.devices,
+        max_size: args
+            .max_size
+            .or(config.max_size)
+            .unwrap_or(DEFAULT_MAX_SIZE),
+    });
+
+    let listen = match args.listen.or(config.listen) {
+        Some(listen) => listen,
+        None => DEFAULT_LISTEN.parse()?,
+    };
+    let listener = TcpListener::bind(listen).await?;
+    println!("Listening on smtp://{}", listener.local_addr()?);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let gateway = gateway.clone();
+        tokio::spawn(async move {
+            let (reader, writer) = stream.into_split();
+            if let Err(er) = gateway.session(BufReader::new(reader), writer).await {
+                eprintln!("{} {}: {}", "smtp session with".red(), peer, er);
+            }
+        });
+    }
+}
+
+/// A line longer than allowed, the session can't find the next line and ends
+#[derive(Debug)]
+struct LineTooLong;
+
+impl std::fmt::Display for LineTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("line too long")
+    }
+}
+
+impl std::error::Error for LineTooLong {}
+
+/// Read a line of at most `limit` bytes, `None` at the end of the stream
+async fn read_line<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+    limit: usize,
+) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let mut bounded = reader.take(limit as u64 + 1);
+    let n = tokio::time::timeout(IDLE, bounded.read_until(b'\n', &mut line)).await??;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.len() > limit && !line.ends_with(b"\n") {
+        bail!(LineTooLong);
+    }
+    while line.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+async fn write<W: AsyncWrite + Unpin>(writer: &mut W, reply: &str) -> Result<()> {
+    writer.write_all(reply.as_bytes()).await?;
+    writer.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+fn decode_base64(s: &[u8]) -> Option<Vec<u8>> {
+    let s = s
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect::<Vec<_>>();
+    BASE64_STANDARD.decode(s).ok()
+}
+
+/// The address inside `<...>` of `FROM:<a@b>` or `TO:<a@b>`
+fn path_address(arg: &str) -> &str {
+    let arg = arg.split_once(':').map_or(arg, |(_, rest)| rest).trim();
+    match (arg.find('<'), arg.find('>')) {
+        (Some(start), Some(end)) if start < end => &arg[start + 1..end],
+        _ => arg.split_whitespace().next().unwrap_or_default(),
+    }
+}
+
+impl Gateway {
+    async fn session<R, W>(&self, mut reader: R, mut writer: W) -> Result<()>
+    where
+        R: AsyncRead + AsyncBufReadExt + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        match self.commands(&mut reader, &mut writer).await {
+            Err(er) if er.is::<LineTooLong>() => write(&mut writer, "500 Line too long").await,
+            done => done,
+        }
+    }
+
+    async fn commands<R, W>(&self, mut reader: R, mut writer: W) -> Result<()>
+    where
+        R: AsyncRead + AsyncBufReadExt + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        write(&mut writer, "220 barsk ESMTP ready").await?;
+
+        let mut authed = self.users.is_empty();
+        let mut from = None;
+        let mut devices = Vec::<String>::new();
+
+        while let Some(line) = read_line(&mut reader, MAX_LINE).await? {
+            let line = String::from_utf8_lossy(&line).into_owned();
+            let (verb, arg) = line.split_once(' ').unwrap_or((&line, ""));
+            let verb = verb.to_uppercase();
+
+            match verb.as_str() {
+                "EHLO" => {
+                    let mut lines = vec![
+                        "250-barsk".to_owned(),
+                        format!("250-SIZE {}", self.max_size),
+                    ];
+                    lines.push("250-8BITMIME".to_owned());
+                    if !self.users.is_empty() {
+                        lines.push("250-AUTH PLAIN LOGIN".to_owned());
+                    }
+                    lines.push("250 HELP".to_owned());
+                    write(&mut writer, &lines.join("\r\n")).await?;
+                }
+                "HELO" => write(&mut writer, "250 barsk").await?,
+                "AUTH" if self.users.is_empty() => {
+                    write(&mut writer, "503 AUTH not enabled").await?
+                }
+                "AUTH" => {
+                    authed = self.auth(arg, &mut reader, &mut writer).await?;
+                    let reply = if authed {
+                        "235 Authentication succeeded"
+                    } else {
+                        "535 Authentication failed"
+                    };
+                    write(&mut writer, reply).await?;
+                }
+                "MAIL" if !authed => write(&mut writer, "530 Authentication required").await?,
+                "MAIL" => {
+                    let size = arg.split_whitespace().find_map(|p| {
+                        p.to_uppercase()
+                            .strip_prefix("SIZE=")?
+                            .parse::<usize>()
+                            .ok()
+                    });
+                    if size.is_some_and(|size| size > self.max_size) {
+                        write(&mut writer, "552 Message size exceeds limit").await?;
+                        continue;
+                    }
+                    from = Some(path_address(arg).to_owned());
+                    devices.clear();
+                    write(&mut writer, "250 OK").await?;
+                }
+                "RCPT" if from.is_none() => write(&mut writer, "503 MAIL first").await?,
+                "RCPT" => {
+                    let address = path_address(arg);
+                    let local = address.split('@').next().unwrap_or_default();
+                    let service = self.pusher.service();
+                    let keys = if service.knows(local) {
+                        service.resolve([local])
+                    } else {
+                        service.resolve(&self.devices)
+                    };
+                    if keys.is_empty() {
+                        write(&mut writer, &format!("550 No device or group {local}")).await?;
+                    } else {
+                        devices.extend(
+                            keys.into_iter()
+                                .filter(|k| !devices.contains(k))
+                                .collect::<Vec<_>>(),
+                        );
+                        write(&mut writer, "250 OK").await?;
+                    }
+                }
+                "DATA" if devices.is_empty() => write(&mut writer, "503 RCPT first").await?,
+                "DATA" => {
+                    write(&mut writer, "354 End data with <CR><LF>.<CR><LF>").await?;
+                    let message = match self.data(&mut reader).await {
+                        Ok(Some(message)) => message,
+                        Ok(None) => {
+                            write(&mut writer, "552 Message size exceeds limit").await?;
+                            from = None;
+                            devices.clear();
+                            continue;
+                        }
+                        Err(er) if er.is::<LineTooLong>() => {
+                            write(&mut writer, "552 Message size exceeds limit").await?;
+                            break;
+                        }
+                        Err(er) => return Err(er),
+                    };
+                    let reply = match self.deliver(&message, std::mem::take(&mut devices)).await {
+                        Ok(()) => "250 OK: pushed",
+                        Err(er) => {
+                            eprintln!("{}: {}", "smtp push failed".red(), er);
+                            "451 Push failed, try again later"
+                        }
+                    };
+                    from = None;
+                    write(&mut writer, reply).await?;
+                }
+                "RSET" => {
+                    from = None;
+                    devices.clear();
+                    write(&mut writer, "250 OK").await?;
+                }
+                "NOOP" => write(&mut writer, "250 OK").await?,
+                "QUIT" => {
+                    write(&mut writer, "221 Bye").await?;
+                    break;
+                }
+                _ => write(&mut writer, "502 Command not implemented").await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// AUTH PLAIN and AUTH LOGIN, with or without the initial response
+    async fn auth<R, W>(&self, arg: &str, reader: &mut R, writer: &mut W) -> Result<bool>
+    where
+        R: AsyncBufReadExt + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let (mechanism, initial) = arg.split_once(' ').unwrap_or((arg, ""));
+        let mut answer = async |prompt: &str, initial: &str| -> Result<Vec<u8>> {
+            let line = if initial.is_empty() {
+                write(writer, &format!("334 {prompt}")).await?;
+                read_line(reader, MAX_LINE).await?.unwrap_or_default()
+            } else {
+                initial.as_bytes().to_vec()
+            };
+            Ok(decode_base64(&line).unwrap_or_default())
+        };
+
+        let (user, password) = match mechanism.to_uppercase().as_str() {
+            "PLAIN" => {
+                let plain = answer("", initial).await?;
+                let mut parts = plain.split(|b| *b == 0).skip(1);
+                (
+                    String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned(),
+                    String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned(),
+                )
+            }
+            "LOGIN" => {
+                let user = answer("VXNlcm5hbWU6", initial).await?;
+                let password = answer("UGFzc3dvcmQ6", "").await?;
+                (
+                    String::from_utf8_lossy(&user).into_owned(),
+                    String::from_utf8_lossy(&password).into_owned(),
+                )
+            }
+            _ => return Ok(false),
+        };
+        Ok(self
+            .users
+            .get(&user)
+            .is_some_and(|p| same_token(p, &password)))
+    }
+
+    /// Read the message up to the lone dot, `None` if it is too large
+    async fn data<R: AsyncBufReadExt + Unpin>(&self, reader: &mut R) -> Result<Option<Vec<u8>>> {
+        let mut message = Vec::new();
+        let mut too_large = false;
+        loop {
+            let Some(line) = read_line(reader, self.max_size).await? else {
+                bail!("Connection closed in DATA");
+            };
+            if line == b"." {
+                break;
+            }
+            // Keep reading a message that is too large, to answer after its end
+            if too_large {
+                continue;
+            }
+            let line = line.strip_prefix(b".").unwrap_or(&line);
+            message.extend_from_slice(line);
+            message.extend_from_slice(b"\r\n");
+            too_large = message.len() > self.max_size;
+        }
+        Ok((!too_large).then_some(message))
+    }
+
+    async fn deliver(&self, message: &[u8], devices: Vec<String>) -> Result<()> {
+        let mail = Mail::parse(message);
+
+        let mut fields = Map::new();
+        if let Some(subject) = mail.subject.filter(|s| !s.is_empty()) {
+            fields.insert("title".to_owned(), subject.into());
+        }
+        let body = if mail.body.is_empty() {
+            "(empty message)".to_owned()
+        } else {
+            mail.body
+        };
+        fields.insert("body".to_owned(), body.into());
+        let mut push = serde_json::from_value::<Push>(Value::Object(fields))?;
+        push.update_level(mail.urgent.then_some(Level::TimeSensitive));
+
+        self.pusher.push(&push, &devices).await?;
+        println!("{}: {}", "pushed mail".green(), push.label());
+        Ok(())
+    }
+}
+
+/// What a push needs of an email
+#[derive(Debug, Default)]
+struct Mail {
+    subject: Option<String>,
+    body: String,
+    urgent: bool,
+}
+
+/// Split headers from the body and unfold the headers, names are lowercase
+fn split_headers(part: &[u8]) -> (Vec<(String, String)>, &[u8]) {
+    let (head, body) = match find(part, b"\r\n\r\n") {
+        Some(i) => (&part[..i], &part[i + 4..]),
+        None => match find(part, b"\n\n") {
+            Some(i) => (&part[..i], &part[i + 2..]),
+            None => (part, &[][..]),
+        },
+    };
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in String::from_utf8_lossy(head).lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_owned()));
+        }
+    }
+    (headers, body)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// A parameter like `boundary` of a header value like `multipart/mixed; boundary="abc"`
+fn parameter<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value.split(';').skip(1).find_map(|p| {
+        let (n, v) = p.split_once('=')?;
+        n.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| v.trim().trim_matches('"'))
+    })
+}
+
+fn decode_quoted_printable(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'=' if s[i + 1..].starts_with(b"\r\n") => i += 3,
+            b'=' if s[i + 1..].starts_with(b"\n") => i += 2,
+            b'=' if i + 2 < s.len()
+                && s[i + 1].is_ascii_hexdigit()
+                && s[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = std::str::from_utf8(&s[i + 1..i + 3]).unwrap_or_default();
+                out.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+                i += 3;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Decode RFC 2047 words like `=?UTF-8?B?5L2g5aW9?=` in a header
+fn decode_words(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+
+    while let Some(start) = rest.find("=?") {
+        let word = rest[start + 2..].splitn(3, '?').collect::<Vec<_>>();
+        let decoded = match word.as_slice() {
+            [_, encoding, text] if text.contains("?=") => {
+                let text = &text[..text.find("?=").unwrap_or_default()];
+                let bytes = match encoding.to_uppercase().as_str() {
+                    "B" => decode_base64(text.as_bytes()),
+                    "Q" => Some(decode_quoted_printable(text.replace('_', " ").as_bytes())),
+                    _ => None,
+                };
+                bytes.map(|b| (String::from_utf8_lossy(&b).into_owned(), text.len()))
+            }
+            _ => None,
+        };
+        let Some((decoded, len)) = decoded else {
+            out.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            after_word = false;
+            continue;
+        };
+
+        // Whitespace between two encoded words is not part of the text
+        let between = &rest[..start];
+        if !(after_word && between.trim().is_empty()) {
+            out.push_str(between);
+        }
+        out.push_str(&decoded);
+        let charset_and_encoding = word[0].len() + word[1].len() + 2;
+        rest = &rest[start + 2 + charset_and_encoding + len + 2..];
+        after_word = true;
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The text of a part: text/plain preferred, html with its tags removed otherwise
+fn text(headers: &[(String, String)], body: &[u8], depth: usize) -> Option<String> {
+    let content_type = header(headers, "content-type").unwrap_or("text/plain");
+    let kind = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    if kind.starts_with("multipart/") {
+        if depth >= MAX_DEPTH {
+            return None;
+        }
+        let boundary = format!("--{}", parameter(content_type, "boundary")?);
+        let parts = String::from_utf8_lossy(body)
+            .split(&boundary)
+            .skip(1)
+            .take_while(|part| !part.starts_with("--"))
+            .map(|part| part.trim_start_matches(['\r', '\n']).as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let texts = parts
+            .iter()
+            .filter_map(|part| {
+                let (headers, body) = split_headers(part);
+                let html = header(&headers, "content-type")
+                    .is_some_and(|t| t.to_lowercase().starts_with("text/html"));
+                Some((html, text(&headers, body, depth + 1)?))
+            })
+            .collect::<Vec<_>>();
+        return texts
+            .iter()
+            .find(|(html, _)| !html)
+            .or(texts.first())
+            .map(|(_, text)| text.clone());
+    }
+    if !kind.starts_with("text/") {
+        return None;
+    }
+
+    let body = match header(headers, "content-transfer-encoding").map(|e| e.to_lowercase()) {
+        Some(e) if e == "base64" => decode_base64(body).unwrap_or_default(),
+        Some(e) if e == "quoted-printable" => decode_quoted_printable(body),
+        _ => body.to_vec(),
+    };
+    let text = String::from_utf8_lossy(&body).into_owned();
+    if kind == "text/html" {
+        let mut plain = String::with_capacity(text.len());
+        let mut in_tag = false;
+        for c in text.chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                c if !in_tag => plain.push(c),
+                _ => {}
+            }
+        }
+        return Some(plain);
+    }
+    Some(text)
+}
+
+impl Mail {
+    fn parse(message: &[u8]) -> Self {
+        let (headers, body) = split_headers(message);
+        let urgent = header(&headers, "x-priority").is_some_and(|p| p.starts_with(['1', '2']))
+            || header(&headers, "importance").is_some_and(|i| i.eq_ignore_ascii_case("high"));
+        Mail {
+            subject: header(&headers, "subject").map(decode_words),
+            body: text(&headers, body, 0)
+                .unwrap_or_default()
+                .replace("\r\n", "\n")
+                .trim()
+                .to_owned(),
+            urgent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn gateway(max_size: usize) -> Gateway {
+        let service = serde_json::from_value::<Service>(json!({
+            "devices": { "phone": "phonekey" },
+        }))
+        .unwrap();
+        let encryption = serde_json::from_value::<Encryption>(json!({})).unwrap();
+        Gateway {
+            pusher: Pusher::new(service, encryption).unwrap(),
+            users: HashMap::from([("user".to_owned(), "secret".to_owned())]),
+            devices: Vec::new(),
+            max_size,
+        }
+    }
+
+    /// Run a session on `input` and return the reply codes
+    async fn replies(gateway: &Gateway, input: &[u8]) -> Vec<String> {
+        let mut output = Vec::new();
+        gateway.session(input, &mut output).await.unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| line[..3].to_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn commands_in_order() {
+        let gateway = gateway(1024);
+        let input = b"EHLO me\r\nMAIL FROM:<a@b>\r\nRCPT TO:<x@y>\r\nDATA\r\n\
+            AUTH PLAIN AHVzZXIAc2VjcmV0\r\nMAIL FROM:<a@b>\r\nRCPT TO:<nobody@y>\r\n\
+            RCPT TO:<phone@y>\r\nRSET\r\nDATA\r\nQUIT\r\n";
+        let codes = replies(&gateway, input).await;
+        assert_eq!(
+            codes,
+            [
+                "220", "250", "250", "250", "250", "250", // greeting and EHLO
+                "530", "503", "503", // AUTH needed, RCPT before MAIL, DATA before RCPT
+                "235", "250", "550", "250", // AUTH, MAIL, unknown and known recipient
+                "250", "503", "221", // RSET, DATA without RCPT, QUIT
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_password() {
+        let gateway = gateway(1024);
+        // user and "wrong"
+        let codes = replies(&gateway, b"AUTH PLAIN AHVzZXIAd3Jvbmc=\r\nQUIT\r\n").await;
+        assert_eq!(codes, ["220", "535", "221"]);
+    }
+
+    #[tokio::test]
+    async fn long_command_line() {
+        let gateway = gateway(1024);
+        let mut input = b"HELO ".to_vec();
+        input.extend(std::iter::repeat_n(b'x', MAX_LINE * 2));
+        input.extend(b"\r\nQUIT\r\n");
+        assert_eq!(replies(&gateway, &input).await, ["220", "500"]);
+    }
+
+    #[tokio::test]
+    async fn large_messages() {
+        let gateway = gateway(64);
+        let mut input = b"HELO me\r\nAUTH PLAIN AHVzZXIAc2VjcmV0\r\nMAIL FROM:<a@b> SIZE=100\r\n\
+            MAIL FROM:<a@b>\r\nRCPT TO:<phone@y>\r\nDATA\r\n"
+            .to_vec();
+        for _ in 0..10 {
+            input.extend(b"0123456789\r\n");
+        }
+        input.extend(b".\r\nMAIL FROM:<a@b>\r\nRCPT TO:<phone@y>\r\nDATA\r\n");
+        input.extend(std::iter::repeat_n(b'x', 1000));
+        input.extend(b"\r\n.\r\nQUIT\r\n");
+        assert_eq!(
+            replies(&gateway, &input).await,
+            [
+                "220", "250", "235", "552", "250", "250", "354", "552", // too many lines
+                "250", "250", "354", "552", // one endless line ends the session
+            ]
+        );
+    }
+
+    #[test]
+    fn quoted_printable() {
+        assert_eq!(decode_quoted_printable(b"a=3Db=C3=A9"), "a=bé".as_bytes());
+        assert_eq!(
+            decode_quoted_printable(b"soft=\r\nbreak=\nhere"),
+            b"softbreakhere"
+        );
+        assert_eq!(decode_quoted_printable(b"=+F=-1=G0=4"), b"=+F=-1=G0=4");
+        assert_eq!(decode_quoted_printable(b"=e9"), [0xe9]);
+    }
+
+    #[test]
+    fn base64_with_line_breaks() {
+        assert_eq!(decode_base64(b"aGVs\r\nbG8=").unwrap(), b"hello");
+        assert!(decode_base64(b"not base64!").is_none());
+    }
+
+    #[test]
+    fn encoded_words() {
+        assert_eq!(decode_words("=?UTF-8?B?5L2g5aW9?= world"), "你好 world");
+        assert_eq!(
+            decode_words("=?utf-8?q?caf=C3=A9_au?= =?utf-8?q?_lait?="),
+            "café au lait"
+        );
+        assert_eq!(decode_words("plain =? text"), "plain =? text");
+    }
+
+    #[test]
+    fn multipart_prefers_plain_text() {
+        let message = b"Subject: =?UTF-8?Q?Hi=21?=\r\nX-Priority: 1\r\n\
+            Content-Type: multipart/alternative; boundary=\"b\"\r\n\r\n\
+            --b\r\nContent-Type: text/html\r\n\r\n<p>html</p>\r\n\
+            --b\r\nContent-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n\r\n\
+            cGxhaW4=\r\n--b--\r\n";
+        let mail = Mail::parse(message);
+        assert_eq!(mail.subject.as_deref(), Some("Hi!"));
+        assert_eq!(mail.body, "plain");
+        assert!(mail.urgent);
+    }
+
+    #[test]
+    fn multipart_depth_is_capped() {
+        fn nested(depth: usize) -> String {
+            let mut part = "Content-Type: text/plain\r\n\r\ndeep\r\n".to_owned();
+            for level in 0..depth {
+                part = format!(
+                    "Content-Type: multipart/mixed; boundary=\"b{level}\"\r\n\r\n\
+                     --b{level}\r\n{part}--b{level}--\r\n"
+                );
+            }
+            part
+        }
+        assert_eq!(Mail::parse(nested(MAX_DEPTH).as_bytes()).body, "deep");
+        assert_eq!(Mail::parse(nested(MAX_DEPTH + 1).as_bytes()).body, "");
+        assert_eq!(Mail::parse(nested(1000).as_bytes()).body, "");
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anstream::{eprintln, println};
+use anyhow::{Result, bail};
+use clap::Args;
+use owo_colors::OwoColorize;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::bark::Service;
+use crate::output::print_results;
+use crate::send::{Delivery, Envelope};
+use crate::state::{create_json, read_json, state_dir, write_json};
+use crate::time::{now, parse_duration};
+
+#[derive(Args, Debug)]
+pub struct Outbox {
+    /// Directory that failed pushes are queued in, default is "outbox" in the state directory
+    #[arg(long, env = "BARSK_SPOOL_DIR", value_name = "DIR", global = true)]
+    spool_dir: Option<PathBuf>,
+
+    /// How long a queued push may still be delivered
+    #[arg(long, value_name = "DURATION", default_value = "1d", value_parser = parse_duration)]
+    queue_expire: Duration,
+
+    /// How many times a queued push is tried before it is dropped
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    queue_attempts: u32,
+}
+
+/// A push waiting in the outbox, stored as one json file
+#[derive(Serialize, Deserialize, Debug)]
+struct Item {
+    envelope: Envelope,
+    queued_at: u64,
+    expires_at: u64,
+    attempts: u32,
+    max_attempts: u32,
+    last_error: Option<String>,
+}
+
+fn last_error(results: &[Delivery]) -> Option<String> {
+    results.iter().rev().find_map(|d| {
+        d.error
+            .clone()
+            .or_else(|| d.message.clone().filter(|_| !d.is_success()))
+    })
+}
+
+impl Outbox {
+    pub fn dir(&self) -> PathBuf {
+        self.spool_dir
+            .clone()
+            .unwrap_or_else(|| state_dir().join("outbox"))
+    }
+
+    /// Queue the devices that failed with a retryable error, return the path of the new item
+    pub async fn queue(
+        &self,
+        envelope: &Envelope,
+        results: &[Delivery],
+    ) -> Result<Option<PathBuf>> {
+        let devices = results
+            .iter()
+            .filter(|d| d.is_retryable())
+            .map(|d| d.device.clone())
+            .collect::<Vec<_>>();
+        if devices.is_empty() {
+            return Ok(None);
+        }
+
+        let queued_at = now();
+        let item = Item {
+            envelope: envelope.only(devices),
+            queued_at,
+            expires_at: queued_at + self.queue_expire.as_secs(),
+            attempts: 1,
+            max_attempts: self.queue_attempts,
+            last_error: last_error(results),
+        };
+
+        // Names sort in the order the pushes were queued, the sequence tells apart
+        // pushes queued in the same millisecond
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        for seq in 0..1000 {
+            let path = self
+                .dir()
+                .join(format!("{millis:016}-{}-{seq:03}.json", std::process::id()));
+            match create_json(&path, &item).await {
+                Ok(()) => return Ok(Some(path)),
+                Err(er) if er.kind() == ErrorKind::AlreadyExists => continue,
+                Err(er) => return Err(er.into()),
+            }
+        }
+        bail!("No free name in the outbox for {millis}")
+    }
+
+    async fn items(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        let mut entries = match fs::read_dir(self.dir()).await {
+            Ok(entries) => entries,
+            Err(er) if er.kind() == ErrorKind::NotFound => return Ok(paths),
+            Err(er) => return Err(er.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Retry every queued push in the order they were queued
+    pub async fn flush(&self, client: &Client, service: &Service) -> Result<()> {
+        let (mut sent, mut pending, mut dropped, mut skipped) = (0, 0, 0, 0);
+
+        for path in self.items().await? {
+            let name = item_name(&path);
+            let mut item = match read_json::<Item>(&path).await {
+                Ok(Some(item)) => item,
+                Ok(None) => continue,
+                Err(er) => {
+                    eprintln!("{}: {}: {}", "unreadable".red(), name, er);
+                    skipped += 1;
+                    continue;
+                }
+            };
+
+            if item.expires_at <= now() {
+                eprintln!("{}: {}", "expired".yellow(), name);
+                fs::remove_file(&path).await?;
+                dropped += 1;
+                continue;
+            }
+
+            item.envelope.resolve(service);
+            let results = item.envelope.deliver(client).await?;
+            print_results(&results);
+
+            let failed = results
+                .iter()
+                .filter(|d| d.is_retryable())
+                .map(|d| d.device.clone())
+                .collect::<Vec<_>>();
+            item.attempts += 1;
+
+            if failed.is_empty() {
+                fs::remove_file(&path).await?;
+                sent += 1;
+            } else if item.attempts >= item.max_attempts {
+                eprintln!(
+                    "{}: {} after {} attempts",
+                    "dropped".red(),
+                    name,
+                    item.attempts
+                );
+                fs::remove_file(&path).await?;
+                dropped += 1;
+            } else {
+                item.envelope = item.envelope.only(failed);
+                item.last_error = last_error(&results);
+                write_json(&path, &item).await?;
+                pending += 1;
+            }
+        }
+
+        println!(
+            "Flushed outbox: {} sent, {} pending, {} dropped, {} skipped",
+            sent.green(),
+            pending.yellow(),
+            dropped.red(),
+            skipped.red()
+        );
+        Ok(())
+    }
+}
+
+fn item_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,124 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Directory that barsk keeps its local state in, e.g. `~/.local/state/barsk`
+pub fn state_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("BARSK_STATE_DIR") {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = env::var_os("XDG_STATE_HOME") {
+        return PathBuf::from(dir).join("barsk");
+    }
+    if let Some(dir) = env::var_os("LOCALAPPDATA") {
+        return PathBuf::from(dir).join("barsk");
+    }
+    if let Some(home) = env::var_os("HOME") {
+        return PathBuf::from(home).join(".local/state/barsk");
+    }
+    PathBuf::from(".barsk")
+}
+
+/// Read a json state file, a missing file is `None`
+pub async fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match fs::read_to_string(path).await {
+        Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+        Err(er) if er.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(er) => Err(er.into()),
+    }
+}
+
+/// Write a json state file through a temporary file, so readers never see half of it
+pub async fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let tmp = tmp_path(path);
+    write_private(&tmp, &serde_json::to_vec_pretty(value)?).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Write a new file that only the user can read, state files hold pushes that may be private
+async fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(data).await?;
+    file.flush().await
+}
+
+/// A temporary file next to `path` that no other writer uses
+pub fn tmp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_extension(format!("{}-{n}.tmp", std::process::id()))
+}
+
+/// Write a new json state file, fails with `AlreadyExists` instead of replacing a file
+pub async fn create_json<T: Serialize>(path: &Path, value: &T) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let tmp = tmp_path(path);
+    write_private(&tmp, &serde_json::to_vec_pretty(value)?).await?;
+    // Linking fails if the name is taken, and readers never see half of the file
+    let linked = fs::hard_link(&tmp, path).await;
+    fs::remove_file(&tmp).await?;
+    linked
+}
+
+/// An exclusive lock on a state file, released when dropped
+pub struct Lock {
+    _file: std::fs::File,
+}
+
+/// Wait until no other barsk process holds the lock of `path`, then hold it
+pub async fn lock(path: &Path) -> Result<Lock> {
+    let path = path.with_extension("lock");
+    tokio::task::spawn_blocking(move || {
+        let file = open_lock(&path)?;
+        file.lock()?;
+        Ok(Lock { _file: file })
+    })
+    .await?
+}
+
+/// Hold the lock of `path` if no other barsk process holds it
+pub async fn try_lock(path: &Path) -> Result<Option<Lock>> {
+    let path = path.with_extension("lock");
+    tokio::task::spawn_blocking(move || {
+        let file = open_lock(&path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Lock { _file: file })),
+            Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+            Err(std::fs::TryLockError::Error(er)) => Err(er.into()),
+        }
+    })
+    .await?
+}
+
+fn open_lock(path: &Path) -> std::io::Result<std::fs::File> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// 64 bit FNV-1a, stable across builds unlike the std hasher
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anstream::{eprintln, println};
+use anyhow::{Result, anyhow, bail};
+use clap::Args;
+use owo_colors::OwoColorize;
+use regex::Regex;
+use serde::Deserialize;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+
+use crate::bark::{Encryption, Service};
+use crate::dedup::{RateLimit, TokenBucket};
+use crate::send::Pusher;
+use crate::template::{PushTemplate, de_option_regex};
+
+static DEFAULT_LISTEN: &str = "udp://127.0.0.1:5514";
+/// Largest message of a TCP connection, larger ones end the connection
+const MAX_FRAME: usize = 64 * 1024;
+
+static FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+static SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+#[derive(Args, Debug)]
+pub struct SyslogArgs {
+    /// Where to receive messages, udp://HOST:PORT or tcp://HOST:PORT, default is udp://127.0.0.1:5514
+    #[arg(long, value_name = "URL")]
+    listen: Option<String>,
+}
+
+/// The `syslog` section of the configuration file
+#[derive(Deserialize, Default, Debug)]
+pub struct SyslogConfig {
+    #[serde(default)]
+    listen: Option<String>,
+
+    /// Every rule that matches a message pushes it
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Rule {
+    /// Facilities by name or number, any if empty
+    #[serde(default)]
+    facility: Vec<String>,
+
+    /// The least severe severity that matches, e.g. "warning" matches warning, err, crit...
+    #[serde(default)]
+    severity: Option<String>,
+
+    /// Program name, the tag of RFC 3164 or the app name of RFC 5424
+    #[serde(default)]
+    program: Option<String>,
+
+    /// Regular expression the message must match, named groups are template variables
+    #[serde(default, rename = "match", deserialize_with = "de_option_regex")]
+    pattern: Option<Regex>,
+
+    /// Most pushes of the rule in a period, e.g. 10/1m
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+
+    /// Devices of the push, the default devices if empty
+    #[serde(default)]
+    devices: Vec<String>,
+
+    /// Templates of push fields, variables are host, program, facility, severity, level and message
+    #[serde(flatten)]
+    template: PushTemplate,
+}
+
+/// A syslog message of either RFC
+#[derive(Debug, Default, PartialEq)]
+struct Message {
+    facility: u8,
+    severity: u8,
+    host: String,
+    program: String,
+    text: String,
+}
+
+fn code(names: &[&str], name: &str) -> Option<u8> {
+    let name = name.trim().to_lowercase();
+    let name = match name.as_str() {
+        "emergency" | "panic" => "emerg",
+        "critical" => "crit",
+        "error" => "err",
+        "warn" => "warning",
+        "informational" => "info",
+        name => name,
+    };
+    name.parse()
+        .ok()
+        .or_else(|| names.iter().position(|n| *n == name).map(|i| i as u8))
+}
+
+/// Split the first space separated field off `s`
+fn field(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    s.split_once(' ').unwrap_or((s, ""))
+}
+
+impl Message {
+    fn parse(raw: &str) -> Option<Self> {
+        let raw = raw.trim_end_matches(['\r', '\n', '\0']);
+        let rest = raw.strip_prefix('<')?;
+        let (pri, rest) = rest.split_once('>')?;
+        let pri = pri.parse::<u16>().ok().filter(|pri| *pri < 192)?;
+        let mut message = Message {
+            facility: (pri / 8) as u8,
+            severity: (pri % 8) as u8,
+            ..Default::default()
+        };
+
+        if let Some(rest) = rest.strip_prefix("1 ") {
+            // RFC 5424: VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG
+            let (_timestamp, rest) = field(rest);
+            let (host, rest) = field(rest);
+            let (program, rest) = field(rest);
+            let (_procid, rest) = field(rest);
+            let (_msgid, rest) = field(rest);
+            let rest = rest.trim_start();
+            let text = match rest.strip_prefix('-') {
+                Some(text) => text,
+                None => skip_structured_data(rest),
+            };
+            message.host = host.trim_start_matches('-').to_owned();
+            message.program = program.trim_start_matches('-').to_owned();
+            message.text = text.trim_start().trim_start_matches('\u{feff}').to_owned();
+        } else {
+            // RFC 3164: Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MSG, the header is often incomplete
+            let mut rest = rest;
+            let stamped = rest.len() > 16
+                && rest.as_bytes()[3] == b' '
+                && rest.as_bytes()[6] == b' '
+                && rest.as_bytes()[9] == b':';
+            // Not a timestamp after all if it doesn't end on a character boundary
+            if stamped && let Some(after_stamp) = rest.get(16..) {
+                rest = after_stamp;
+                let (host, after) = field(rest);
+                if !host.ends_with(':') && !host.contains('[') {
+                    message.host = host.to_owned();
+                    rest = after;
+                }
+            }
+            match rest.find(':') {
+                Some(colon) if !rest[..colon].contains(' ') => {
+                    let tag = &rest[..colon];
+                    message.program = tag.split('[').next().unwrap_or(tag).to_owned();
+                    message.text = rest[colon + 1..].trim_start().to_owned();
+                }
+                _ => message.text = rest.trim_start().to_owned(),
+            }
+        }
+        Some(message)
+    }
+
+    /// The push level of the severity
+    fn level(&self) -> &'static str {
+        match self.severity {
+            0..=2 => "critical",
+            3 => "timeSensitive",
+            4 | 5 => "active",
+            _ => "passive",
+        }
+    }
+
+    fn variable(&self, name: &str) -> Option<String> {
+        match name {
+            "host" => Some(self.host.clone()),
+            "program" => Some(self.program.clone()),
+            "message" => Some(self.text.clone()),
+            "facility" => FACILITIES
+                .get(self.facility as usize)
+                .map(|s| s.to_string()),
+            "severity" => SEVERITIES
+                .get(self.severity as usize)
+                .map(|s| s.to_string()),
+            "level" => Some(self.level().to_owned()),
+            _ => None,
+        }
+    }
+}
+
+/// Skip `[id param="value"]...` of RFC 5424, values may contain escaped `]`
+fn skip_structured_data(s: &str) -> &str {
+    let mut depth = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => depth = true,
+            ']' => depth = false,
+            ' ' if !depth => return &s[i..],
+            _ => {}
+        }
+    }
+    ""
+}
+
+impl Rule {
+    /// The template variables if the rule matches
+    fn matches(&self, message: &Message) -> Option<HashMap<String, String>> {
+        if !self.facility.is_empty()
+            && !self
+                .facility
+                .iter()
+                .any(|f| code(&FACILITIES, f) == Some(message.facility))
+        {
+            return None;
+        }
+        if let Some(severity) = &self.severity
+            && code(&SEVERITIES, severity).is_none_or(|s| message.severity > s)
+        {
+            return None;
+        }
+        if let Some(program) = &self.program
+            && *program != message.program
+        {
+            return None;
+        }
+
+        let mut captures = HashMap::new();
+        if let Some(pattern) = &self.pattern {
+            let found = pattern.captures(&message.text)?;
+            for name in pattern.capture_names().flatten() {
+                if let Some(value) = found.name(name) {
+                    captures.insert(name.to_owned(), value.as_str().to_owned());
+                }
+            }
+        }
+        Some(captures)
+    }
+}
+
+struct Receiver {
+    rules: Vec<Rule>,
+    buckets: Mutex<Vec<Option<TokenBucket>>>,
+    /// Pushes dropped by the rate limit of each rule, told in its next push
+    dropped: Mutex<Vec<u32>>,
+    pusher: Pusher,
+}
+
+impl Receiver {
+    async fn receive(&self, raw: &str) {
+        let Some(message) = Message::parse(raw) else {
+            eprintln!("{}: {}", "not a syslog message".yellow(), raw.trim_end());
+            return;
+        };
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            let Some(captures) = rule.matches(&message) else {
+                continue;
+            };
+
+            let allowed = self.buckets.lock().await[i]
+                .as_mut()
+                .is_none_or(|bucket| bucket.take());
+            if !allowed {
+                self.dropped.lock().await[i] += 1;
+                continue;
+            }
+            let dropped = std::mem::take(&mut self.dropped.lock().await[i]);
+
+            let push = rule.template.render(|name| {
+                captures
+                    .get(name)
+                    .cloned()
+                    .or_else(|| message.variable(name))
+                    .or_else(|| (name == "dropped").then(|| dropped.to_string()))
+            });
+            let mut push = match push {
+                Ok(push) => push,
+                Err(er) => {
+                    eprintln!("{}: {}", "syslog rule".red(), er);
+                    continue;
+                }
+            };
+            if dropped > 0 {
+                let body = format!(
+                    "{}\n({dropped} more messages were dropped by the rate limit)",
+                    push.body().unwrap_or_default()
+                );
+                push = push.with_body(body);
+            }
+
+            match self.pusher.push(&push, &rule.devices).await {
+                Ok(()) => println!("{}: {}", "pushed syslog".green(), push.label()),
+                Err(er) => eprintln!("{}: {}", "syslog push failed".red(), er),
+            }
+        }
+    }
+}
+
+/// The next message of a TCP connection, framed by octet counting or by newlines (RFC 6587),
+/// `None` at the end of the stream
+async fn next_frame<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let buf = reader.fill_buf().await?;
+    let Some(first) = buf.first() else {
+        return Ok(None);
+    };
+
+    if first.is_ascii_digit() {
+        let mut len = Vec::new();
+        (&mut *reader).take(12).read_until(b' ', &mut len).await?;
+        let len = String::from_utf8_lossy(&len)
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| anyhow!("Bad octet count"))?;
+        if len > MAX_FRAME {
+            bail!("Message of {len} bytes is larger than {MAX_FRAME}");
+        }
+        let mut message = vec![0; len];
+        reader.read_exact(&mut message).await?;
+        Ok(Some(message))
+    } else {
+        let mut message = Vec::new();
+        (&mut *reader)
+            .take(MAX_FRAME as u64 + 1)
+            .read_until(b'\n', &mut message)
+            .await?;
+        if message.len() > MAX_FRAME {
+            bail!("Message is larger than {MAX_FRAME} bytes");
+        }
+        Ok(Some(message))
+    }
+}
+
+async fn tcp_session(stream: TcpStream, receiver: Arc<Receiver>) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    while let Some(message) = next_frame(&mut reader).await? {
+        receiver.receive(&String::from_utf8_lossy(&message)).await;
+    }
+    Ok(())
+}
+
+pub async fn serve(
+    args: &SyslogArgs,
+    config: SyslogConfig,
+    service: Service,
+    encryption: Encryption,
+) -> Result<()> {
+    if config.rules.is_empty() {
+        bail!("No syslog rules in the configuration file");
+    }
+
+    let mut rules = config.rules;
+    for rule in &mut rules {
+        rule.template.or_insert("title", "{{ host }} {{ program }}");
+        rule.template.or_insert("body", "{{ message }}");
+        rule.template.or_insert("level", "{{ level }}");
+    }
+    let receiver = Arc::new(Receiver {
+        buckets: Mutex::new(
+            rules
+                .iter()
+                .map(|rule| rule.rate_limit.map(TokenBucket::new))
+                .collect(),
+        ),
+        dropped: Mutex::new(vec![0; rules.len()]),
+        rules,
+        pusher: Pusher::new(service, encryption)?,
+    });
+
+    let listen = args
+        .listen
+        .clone()
+        .or(config.listen)
+        .unwrap_or(DEFAULT_LISTEN.to_owned());
+    let (scheme, addr) = listen.split_once("://").unwrap_or(("udp", &listen));
+
+    match scheme {
+        "udp" => {
+            let socket = UdpSocket::bind(addr).await?;
+            println!("Listening on udp://{}", socket.local_addr()?);
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let (len, _) = socket.recv_from(&mut buf).await?;
+                let raw = String::from_utf8_lossy(&buf[..len]).into_owned();
+                let receiver = receiver.clone();
+                tokio::spawn(async move { receiver.receive(&raw).await });
+            }
+        }
+        "tcp" => {
+            let listener = TcpListener::bind(addr).await?;
+            println!("Listening on tcp://{}", listener.local_addr()?);
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                let receiver = receiver.clone();
+                tokio::spawn(async move {
+                    if let Err(er) = tcp_session(stream, receiver).await {
+                        eprintln!("{} {}: {}", "syslog connection of".red(), peer, er);
+                    }
+                });
+            }
+        }
+        _ => bail!("Listen on udp://HOST:PORT or tcp://HOST:PORT, not {listen}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc3164() {
+        let message =
+            Message::parse("<34>Oct 11 22:14:15 mymachine su[123]: 'su root' failed\n").unwrap();
+        assert_eq!(
+            message,
+            Message {
+                facility: 4,
+                severity: 2,
+                host: "mymachine".to_owned(),
+                program: "su".to_owned(),
+                text: "'su root' failed".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn rfc3164_without_header() {
+        let message = Message::parse("<13>sshd: session opened").unwrap();
+        assert_eq!(
+            (message.host.as_str(), message.program.as_str()),
+            ("", "sshd")
+        );
+        assert_eq!(message.text, "session opened");
+
+        let message = Message::parse("<13>just text, no tag").unwrap();
+        assert_eq!(message.text, "just text, no tag");
+    }
+
+    #[test]
+    fn rfc3164_multibyte_at_timestamp_end() {
+        // Looks stamped, but byte 16 is inside "é"
+        let message = Message::parse("<13>abc de fg:xxxxxé rest").unwrap();
+        assert_eq!(message.text, "abc de fg:xxxxxé rest");
+    }
+
+    #[test]
+    fn rfc5424() {
+        let raw = "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 \
+            [exampleSDID@32473 iut=\"3\" eventSource=\"App\\]lication\"] \u{feff}An event";
+        let message = Message::parse(raw).unwrap();
+        assert_eq!(
+            message,
+            Message {
+                facility: 20,
+                severity: 5,
+                host: "mymachine.example.com".to_owned(),
+                program: "evntslog".to_owned(),
+                text: "An event".to_owned(),
+            }
+        );
+
+        let message = Message::parse("<14>1 - - - - - - plain").unwrap();
+        assert_eq!(
+            (message.host.as_str(), message.text.as_str()),
+            ("", "plain")
+        );
+    }
+
+    #[test]
+    fn not_syslog() {
+        assert!(Message::parse("no priority").is_none());
+        assert!(Message::parse("<999>too high").is_none());
+    }
+
+    #[tokio::test]
+    async fn octet_counting_and_newline_frames() {
+        let mut input: &[u8] = b"10 <13>a: one11 <13>b: two\n<13>c: three\n";
+        let mut frames = Vec::new();
+        while let Some(frame) = next_frame(&mut input).await.unwrap() {
+            frames.push(String::from_utf8(frame).unwrap());
+        }
+        assert_eq!(frames, ["<13>a: one", "<13>b: two\n", "<13>c: three\n"]);
+    }
+
+    #[tokio::test]
+    async fn oversized_frames() {
+        let mut input: &[u8] = b"999999999999 <13>a: huge";
+        assert!(next_frame(&mut input).await.is_err());
+
+        let mut input: &[u8] = b"65537 <13>a: huge";
+        assert!(next_frame(&mut input).await.is_err());
+
+        let line = vec![b'x'; MAX_FRAME + 10];
+        assert!(next_frame(&mut line.as_slice()).await.is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, bail};
+use regex::Regex;
+use serde::{Deserialize, de};
+use serde_json::{Map, Value};
+
+use crate::bark::Push;
+
+/// Render `template`, `{{ name }}` is replaced by the value `lookup` gives for `name`, and
+/// `{{ name | fallback }}` by the fallback if the variable is missing or empty
+pub fn render(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+
+        let expr = &rest[start + 2..start + 2 + end];
+        let (name, fallback) = match expr.split_once('|') {
+            Some((name, fallback)) => (name.trim(), Some(fallback.trim())),
+            None => (expr.trim(), None),
+        };
+        match lookup(name).filter(|value| !value.is_empty()) {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(fallback.map(unquote).unwrap_or_default()),
+        }
+
+        rest = &rest[start + 2 + end + 2..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+/// Templates of push fields, like `title = "{{ host }}"` and `level = "critical"`
+#[derive(Deserialize, Default, Debug)]
+#[serde(transparent)]
+pub struct PushTemplate(HashMap<String, String>);
+
+impl PushTemplate {
+    /// Use `template` for a field that has none
+    pub fn or_insert(&mut self, field: &str, template: &str) {
+        self.0
+            .entry(field.to_owned())
+            .or_insert_with(|| template.to_owned());
+    }
+
+    /// Render every field, fields that render empty are left out
+    pub fn render(&self, lookup: impl Fn(&str) -> Option<String>) -> Result<Push> {
+        let mut fields = Map::new();
+        for (name, template) in &self.0 {
+            let value = render(template, &lookup);
+            if !value.is_empty() {
+                fields.insert(name.clone(), Value::String(value));
+            }
+        }
+        let push = serde_json::from_value::<Push>(Value::Object(fields))?;
+        if push.body().is_none() && push.title().is_none() {
+            bail!("push needs a body or a title");
+        }
+        Ok(push)
+    }
+}
+
+/// Deserialize an optional regular expression
+pub fn de_option_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| Regex::new(&pattern).map_err(de::Error::custom))
+        .transpose()
+}
@@ -0,0 +1,127 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow, bail};
+use jiff::{Span, Timestamp, Zoned, civil, tz::TimeZone};
+use serde::{Deserialize, de};
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Parse durations like `90s`, `45m`, `1h30m` or `2d`, a bare number is seconds
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    if s.is_empty() {
+        bail!("Empty duration");
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total = 0u64;
+    let mut digits = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let n = digits
+            .parse::<u64>()
+            .map_err(|_| anyhow!("Invalid duration {s:?}"))?;
+        digits.clear();
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => bail!("Invalid unit {c:?} in duration {s:?}"),
+        };
+        total = n
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(|| anyhow!("Duration {s:?} is too long"))?;
+    }
+    if !digits.is_empty() {
+        bail!("Missing unit in duration {s:?}");
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// Parse how often something repeats, like [`parse_duration`] but never zero
+pub fn parse_period(s: &str) -> Result<Duration> {
+    let period = parse_duration(s)?;
+    if period.is_zero() {
+        bail!("Period {s:?} must be longer than zero");
+    }
+    Ok(period)
+}
+
+/// Deserialize a duration like `5m`
+pub fn de_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    parse_duration(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+/// Deserialize an optional duration like `5m`
+pub fn de_option_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| parse_duration(&s).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Parse `18:00`, `2025-01-31 08:30` or an RFC 3339 timestamp into seconds since the unix epoch.
+/// A time of day that has passed today means tomorrow.
+pub fn parse_at(s: &str) -> Result<u64> {
+    let s = s.trim();
+    if let Ok(ts) = s.parse::<Timestamp>() {
+        return Ok(ts.as_second().max(0) as u64);
+    }
+    if let Ok(dt) = s.parse::<civil::DateTime>() {
+        let at = dt.to_zoned(TimeZone::system())?;
+        return Ok(at.timestamp().as_second().max(0) as u64);
+    }
+
+    let time = s
+        .parse::<civil::Time>()
+        .map_err(|_| anyhow!("Invalid time {s:?}, try 18:00 or \"2025-01-31 08:30\""))?;
+    let now = Zoned::now();
+    let mut at = now.with().time(time).build()?;
+    if at <= now {
+        at = at.checked_add(Span::new().days(1))?;
+    }
+    Ok(at.timestamp().as_second().max(0) as u64)
+}
+
+/// Local date and time of seconds since the unix epoch
+pub fn format_local(secs: u64) -> String {
+    Timestamp::from_second(secs as i64)
+        .map(|ts| {
+            ts.to_zoned(TimeZone::system())
+                .strftime("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|_| secs.to_string())
+}
+
+/// Format a duration the way `parse_duration` reads it, like `1h2m3s`
+pub fn format_duration(duration: Duration) -> String {
+    let mut rest = duration.as_secs();
+    let mut out = String::new();
+    for (unit, size) in [('d', 24 * 60 * 60), ('h', 60 * 60), ('m', 60), ('s', 1)] {
+        if rest >= size || (unit == 's' && out.is_empty()) {
+            out.push_str(&format!("{}{unit}", rest / size));
+            rest %= size;
+        }
+    }
+    out
+}
@@ -0,0 +1,326 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anstream::{eprintln, println};
+use anyhow::{Result, bail};
+use clap::Args;
+use owo_colors::OwoColorize;
+
+use crate::bark::{Encryption, Service};
+use crate::send::Pusher;
+use crate::template::PushTemplate;
+use crate::time::{format_duration, parse_period};
+
+const DEFAULT_EVERY: Duration = Duration::from_secs(1);
+/// Clock ticks per second of /proc, fixed for user space
+const USER_HZ: f64 = 100.0;
+
+#[derive(Args, Debug)]
+pub struct WaitPidArgs {
+    /// Processes to wait for
+    #[arg(required_unless_present = "name")]
+    pids: Vec<u32>,
+
+    /// Also wait for every process with this name, repeat for more names
+    #[arg(long, value_name = "NAME")]
+    name: Vec<String>,
+
+    /// Push as each process exits instead of once when all have
+    #[arg(long)]
+    each: bool,
+
+    /// How often to check the processes, default is 1s
+    #[arg(long, value_name = "DURATION", value_parser = parse_period)]
+    every: Option<Duration>,
+
+    /// Title template of the push, default is the names of the processes
+    #[arg(long, value_name = "TEMPLATE")]
+    title: Option<String>,
+
+    /// Body template of the push, default is how long each process ran
+    #[arg(long, value_name = "TEMPLATE")]
+    body: Option<String>,
+}
+
+struct Process {
+    pid: u32,
+    name: String,
+    /// Clock ticks after boot the process started, tells it from a later one with the same pid
+    started: Option<u64>,
+    runtime: Option<Duration>,
+    /// Wait status, readable while the exited process waits for its parent
+    status: Option<i32>,
+}
+
+/// What /proc tells of a process
+enum State {
+    Running,
+    /// Exited and waiting for its parent, with the wait status if it could be read
+    Zombie(Option<i32>),
+    Gone,
+}
+
+/// Fields of /proc/<pid>/stat after the command name
+fn stat(pid: u32) -> Option<Vec<String>> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    Some(fields.split_whitespace().map(str::to_owned).collect())
+}
+
+fn uptime() -> Option<f64> {
+    fs::read_to_string("/proc/uptime")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Real, effective, saved and filesystem id of a `Uid` or `Gid` line of /proc/<pid>/status
+fn ids(pid: &str, key: &str) -> Option<Vec<u32>> {
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))?;
+    line.split_whitespace().map(|id| id.parse().ok()).collect()
+}
+
+/// Whether /proc shows us the exit status of `pid`, it shows 0 to other users
+fn permitted(pid: u32) -> bool {
+    let pid = pid.to_string();
+    let (Some(my_uids), Some(my_gids)) = (ids("self", "Uid"), ids("self", "Gid")) else {
+        return false;
+    };
+    let (Some(uids), Some(gids)) = (ids(&pid, "Uid"), ids(&pid, "Gid")) else {
+        return false;
+    };
+    let (Some(&fsuid), Some(&fsgid)) = (my_uids.get(3), my_gids.get(3)) else {
+        return false;
+    };
+    fsuid == 0
+        || (uids.iter().take(3).all(|uid| *uid == fsuid)
+            && gids.iter().take(3).all(|gid| *gid == fsgid))
+}
+
+/// State of the process `pid` that started at `started`, a process that started at another
+/// time reuses the pid of one that is gone
+fn state(pid: u32, started: Option<u64>) -> State {
+    let Some(fields) = stat(pid) else {
+        return State::Gone;
+    };
+    if started.is_some() && fields.get(19).and_then(|ticks| ticks.parse().ok()) != started {
+        return State::Gone;
+    }
+    match fields.first().map(String::as_str) {
+        Some("Z" | "X") => State::Zombie(
+            fields
+                .get(49)
+                .and_then(|status| status.parse().ok())
+                .filter(|_| permitted(pid)),
+        ),
+        _ => State::Running,
+    }
+}
+
+/// Exit code or signal of a wait status
+fn describe(status: i32) -> String {
+    match status & 0x7f {
+        0 => format!("exit status {}", (status >> 8) & 0xff),
+        signal => format!("killed by signal {signal}"),
+    }
+}
+
+fn comm(pid: u32) -> Option<String> {
+    fs::read_to_string(format!("/proc/{pid}/comm"))
+        .ok()
+        .map(|name| name.trim_end().to_owned())
+}
+
+/// File name of the program, the command name is cut at 15 bytes
+fn program(pid: u32) -> Option<String> {
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    let argv0 = cmdline.split(|b| *b == 0).next()?;
+    let argv0 = String::from_utf8_lossy(argv0);
+    Path::new(argv0.as_ref())
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+fn find(name: &str) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let me = std::process::id();
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| *pid != me)
+        .filter(|pid| comm(*pid).as_deref() == Some(name) || program(*pid).as_deref() == Some(name))
+        .collect()
+}
+
+impl Process {
+    fn new(pid: u32) -> Self {
+        let started = stat(pid).and_then(|fields| fields.get(19)?.parse().ok());
+        Self {
+            pid,
+            name: comm(pid).unwrap_or_else(|| pid.to_string()),
+            started,
+            runtime: None,
+            status: None,
+        }
+    }
+
+    fn exited(&mut self, waited: Duration, uptime: Option<f64>, status: Option<i32>) {
+        let started = self.started.map(|ticks| ticks as f64 / USER_HZ);
+        let runtime = match (started, uptime) {
+            (Some(started), Some(now)) if now >= started => Duration::from_secs_f64(now - started),
+            _ => waited,
+        };
+        self.runtime = Some(runtime);
+        self.status = status;
+    }
+
+    fn line(&self) -> String {
+        let runtime = self.runtime.map(format_duration).unwrap_or_default();
+        let mut line = format!("{} (pid {}) ran {runtime}", self.name, self.pid);
+        if let Some(status) = self.status {
+            line.push_str(&format!(", {}", describe(status)));
+        }
+        line
+    }
+}
+
+async fn push(pusher: &Pusher, template: &PushTemplate, processes: &[&Process]) -> Result<()> {
+    let mut names = processes
+        .iter()
+        .map(|process| process.name.as_str())
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    names.dedup();
+    let lookup = |name: &str| match name {
+        "name" => Some(names.join(", ")),
+        "pids" => Some(
+            processes
+                .iter()
+                .map(|process| process.pid.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        "count" => Some(processes.len().to_string()),
+        "status" => Some(
+            processes
+                .iter()
+                .filter_map(|process| process.status.map(describe))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        "runtime" => processes
+            .iter()
+            .filter_map(|process| process.runtime)
+            .max()
+            .map(format_duration),
+        "summary" => Some(
+            processes
+                .iter()
+                .map(|process| process.line())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+        _ => None,
+    };
+
+    let push = template.render(lookup)?;
+    pusher.push(&push, &[]).await?;
+    println!("{}: {}", "pushed".green(), push.label());
+    Ok(())
+}
+
+pub async fn wait(args: &WaitPidArgs, service: Service, encryption: Encryption) -> Result<()> {
+    if !Path::new("/proc/self/stat").exists() {
+        bail!("wait-pid reads /proc, which this system doesn't have");
+    }
+
+    let mut pids = args.pids.clone();
+    for name in &args.name {
+        let found = find(name);
+        if found.is_empty() {
+            eprintln!("{}: {}", "no process named".yellow(), name);
+        }
+        pids.extend(found);
+    }
+    pids.sort_unstable();
+    pids.dedup();
+
+    let mut processes = Vec::new();
+    for pid in pids {
+        if matches!(state(pid, None), State::Running) {
+            processes.push(Process::new(pid));
+        } else {
+            eprintln!("{}: {}", "no running process".yellow(), pid);
+        }
+    }
+    if processes.is_empty() {
+        bail!("No process to wait for");
+    }
+
+    let mut template = PushTemplate::default();
+    template.or_insert(
+        "title",
+        args.title.as_deref().unwrap_or("{{ name }} finished"),
+    );
+    template.or_insert("body", args.body.as_deref().unwrap_or("{{ summary }}"));
+    let pusher = Pusher::new(service, encryption)?;
+
+    println!(
+        "{}: {}",
+        "waiting for".green(),
+        processes
+            .iter()
+            .map(|process| format!("{} ({})", process.name, process.pid))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let begun = Instant::now();
+    let every = args.every.unwrap_or(DEFAULT_EVERY);
+    while processes.iter().any(|process| process.runtime.is_none()) {
+        tokio::time::sleep(every).await;
+        let waiting = processes
+            .iter()
+            .filter(|process| process.runtime.is_none())
+            .map(|process| (process.pid, process.started))
+            .collect::<Vec<_>>();
+        let (states, uptime) = tokio::task::spawn_blocking(move || {
+            let states = waiting
+                .into_iter()
+                .map(|(pid, started)| state(pid, started))
+                .collect::<Vec<_>>();
+            (states, uptime())
+        })
+        .await?;
+
+        let waiting = processes
+            .iter_mut()
+            .filter(|process| process.runtime.is_none());
+        for (process, state) in waiting.zip(states) {
+            let status = match state {
+                State::Running => continue,
+                State::Zombie(status) => status,
+                State::Gone => None,
+            };
+            process.exited(begun.elapsed(), uptime, status);
+            println!("{}: {}", "exited".green(), process.line());
+            if args.each
+                && let Err(er) = push(&pusher, &template, &[process]).await
+            {
+                eprintln!("{}: {}", "push failed".red(), er);
+            }
+        }
+    }
+
+    if !args.each {
+        push(&pusher, &template, &processes.iter().collect::<Vec<_>>()).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,385 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, Metadata};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anstream::{eprintln, println};
+use anyhow::{Result, bail};
+use clap::Args;
+use owo_colors::OwoColorize;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::bark::{Encryption, Service};
+use crate::send::Pusher;
+use crate::template::{PushTemplate, de_option_regex};
+use crate::time::{de_option_duration, parse_duration};
+
+const POLL: Duration = Duration::from_millis(500);
+
+#[derive(Args, Debug)]
+pub struct WatchLogArgs {
+    /// Log file to follow, it may be rotated or truncated
+    path: PathBuf,
+
+    /// Push lines that match this regular expression, repeat for more rules
+    #[arg(long = "match", value_name = "REGEX")]
+    patterns: Vec<Regex>,
+
+    /// Title template of the pushes, default is the file name
+    #[arg(long, value_name = "TEMPLATE")]
+    title: Option<String>,
+
+    /// Body template of the pushes, default is the context and the line
+    #[arg(long, value_name = "TEMPLATE")]
+    body: Option<String>,
+
+    /// Lines before a match that go into {{ context }}
+    #[arg(long, short = 'C', value_name = "LINES")]
+    context: Option<usize>,
+
+    /// After a push, count matches of the rule for this long instead of pushing them, e.g. 5m
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    cooldown: Option<Duration>,
+
+    /// Collect matches of the rule for this long and push them together, e.g. 30s
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    batch: Option<Duration>,
+
+    /// Read the file from the beginning instead of only new lines
+    #[arg(long)]
+    from_start: bool,
+}
+
+/// A rule of the `watch_log` section, under the path of the file it watches
+#[derive(Deserialize, Debug)]
+pub struct LogRule {
+    #[serde(rename = "match", default, deserialize_with = "de_option_regex")]
+    pattern: Option<Regex>,
+
+    #[serde(default)]
+    context: Option<usize>,
+
+    #[serde(default, deserialize_with = "de_option_duration")]
+    cooldown: Option<Duration>,
+
+    #[serde(default, deserialize_with = "de_option_duration")]
+    batch: Option<Duration>,
+
+    /// Devices of the push, the default devices if empty
+    #[serde(default)]
+    devices: Vec<String>,
+
+    /// Templates of push fields, variables are path, file, line, context, count and named groups
+    #[serde(flatten)]
+    template: PushTemplate,
+}
+
+/// State of a rule between matches
+#[derive(Default)]
+struct Pending {
+    /// Variables of the matches waiting for the batch to end
+    matches: Vec<HashMap<String, String>>,
+    batch_ends: Option<Instant>,
+    cooldown_ends: Option<Instant>,
+    /// Matches not pushed because of the cooldown, told in the next push
+    cooled: u32,
+}
+
+/// Follows a file by its path, across rotation and truncation
+struct Follower {
+    path: PathBuf,
+    file: Option<File>,
+    identity: Option<u64>,
+    offset: u64,
+    partial: Vec<u8>,
+}
+
+#[cfg(unix)]
+fn identity(meta: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.dev() ^ meta.ino().rotate_left(32))
+}
+
+#[cfg(not(unix))]
+fn identity(_meta: &Metadata) -> Option<u64> {
+    None
+}
+
+impl Follower {
+    fn new(path: PathBuf, from_start: bool) -> Self {
+        let mut follower = Self {
+            path,
+            file: None,
+            identity: None,
+            offset: 0,
+            partial: Vec::new(),
+        };
+        if follower.open() && !from_start {
+            follower.offset = follower
+                .file
+                .as_mut()
+                .and_then(|file| file.seek(SeekFrom::End(0)).ok())
+                .unwrap_or_default();
+        }
+        follower
+    }
+
+    /// Open the file at the path from its beginning
+    fn open(&mut self) -> bool {
+        match File::open(&self.path) {
+            Ok(file) => {
+                self.identity = file.metadata().ok().as_ref().and_then(identity);
+                self.file = Some(file);
+                self.offset = 0;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Complete lines appended since the last call
+    fn lines(&mut self) -> Vec<String> {
+        let mut lines = self.drain();
+
+        let Ok(meta) = fs::metadata(&self.path) else {
+            return lines;
+        };
+        if self.file.is_none() || identity(&meta) != self.identity {
+            // A new file took the path, the rest of the old one was read above
+            if self.open() {
+                lines.extend(self.rest());
+                println!("{}: {}", "following".green(), self.path.display());
+                lines.extend(self.drain());
+            }
+        } else if meta.len() < self.offset
+            && let Some(file) = &mut self.file
+            && file.seek(SeekFrom::Start(0)).is_ok()
+        {
+            // Truncated in place
+            self.offset = 0;
+            lines.extend(self.rest());
+            lines.extend(self.drain());
+        }
+        lines
+    }
+
+    /// The last line of a file that is gone, if it didn't end with a newline
+    fn rest(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.partial);
+        let line = String::from_utf8_lossy(&rest)
+            .trim_end_matches('\r')
+            .to_owned();
+        (!line.is_empty()).then_some(line)
+    }
+
+    fn drain(&mut self) -> Vec<String> {
+        let Some(file) = &mut self.file else {
+            return Vec::new();
+        };
+        let mut buf = Vec::new();
+        match file.read_to_end(&mut buf) {
+            Ok(read) => self.offset += read as u64,
+            Err(er) => {
+                eprintln!("{} {}: {}", "reading".red(), self.path.display(), er);
+                return Vec::new();
+            }
+        }
+        self.partial.extend(buf);
+
+        let Some(end) = self.partial.iter().rposition(|b| *b == b'\n') else {
+            return Vec::new();
+        };
+        let complete = self.partial.drain(..=end).collect::<Vec<_>>();
+        String::from_utf8_lossy(&complete)
+            .lines()
+            .map(|line| line.trim_end_matches('\r').to_owned())
+            .collect()
+    }
+}
+
+impl LogRule {
+    fn from_args(args: &WatchLogArgs, pattern: Regex) -> Self {
+        let mut template = PushTemplate::default();
+        if let Some(title) = &args.title {
+            template.or_insert("title", title);
+        }
+        if let Some(body) = &args.body {
+            template.or_insert("body", body);
+        }
+        Self {
+            pattern: Some(pattern),
+            context: args.context,
+            cooldown: args.cooldown,
+            batch: args.batch,
+            devices: Vec::new(),
+            template,
+        }
+    }
+
+    /// The template variables if the line matches
+    fn matches(
+        &self,
+        path: &Path,
+        line: &str,
+        before: &VecDeque<String>,
+    ) -> Option<HashMap<String, String>> {
+        let mut vars = HashMap::new();
+        if let Some(pattern) = &self.pattern {
+            let found = pattern.captures(line)?;
+            for name in pattern.capture_names().flatten() {
+                if let Some(value) = found.name(name) {
+                    vars.insert(name.to_owned(), value.as_str().to_owned());
+                }
+            }
+        }
+        let skip = before.len().saturating_sub(self.context.unwrap_or(0));
+        let context = before
+            .iter()
+            .skip(skip)
+            .map(String::as_str)
+            .chain([line])
+            .collect::<Vec<_>>()
+            .join("\n");
+        vars.insert("context".to_owned(), context);
+        vars.insert("line".to_owned(), line.to_owned());
+        vars.insert("path".to_owned(), path.display().to_string());
+        if let Some(name) = path.file_name() {
+            vars.insert("file".to_owned(), name.to_string_lossy().into_owned());
+        }
+        Some(vars)
+    }
+}
+
+/// Push the matches of a rule, a batch as one push with lines and contexts joined
+async fn push(pusher: &Pusher, rule: &LogRule, pending: &mut Pending) {
+    let matches = std::mem::take(&mut pending.matches);
+    let Some(first) = matches.first() else {
+        return;
+    };
+    let joined = |name: &str| {
+        matches
+            .iter()
+            .filter_map(|vars| vars.get(name).map(String::as_str))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let lookup = |name: &str| match name {
+        "line" | "context" => Some(joined(name)),
+        "count" => Some(matches.len().to_string()),
+        _ => first.get(name).cloned(),
+    };
+
+    let mut push = match rule.template.render(lookup) {
+        Ok(push) => push,
+        Err(er) => {
+            eprintln!("{}: {}", "watch-log rule".red(), er);
+            return;
+        }
+    };
+    let cooled = std::mem::take(&mut pending.cooled);
+    if cooled > 0 {
+        let body = format!(
+            "{}\n({cooled} more matches during the cooldown)",
+            push.body().unwrap_or_default()
+        );
+        push = push.with_body(body);
+    }
+    if let Some(cooldown) = rule.cooldown {
+        pending.cooldown_ends = Some(Instant::now() + cooldown);
+    }
+
+    match pusher.push(&push, &rule.devices).await {
+        Ok(()) => println!("{}: {}", "pushed".green(), push.label()),
+        Err(er) => eprintln!("{}: {}", "push failed".red(), er),
+    }
+}
+
+pub async fn watch(
+    args: &WatchLogArgs,
+    mut config: HashMap<String, Vec<LogRule>>,
+    service: Service,
+    encryption: Encryption,
+) -> Result<()> {
+    let mut rules = args
+        .patterns
+        .iter()
+        .map(|pattern| LogRule::from_args(args, pattern.clone()))
+        .collect::<Vec<_>>();
+    let key = args.path.to_string_lossy();
+    rules.extend(config.remove(key.as_ref()).unwrap_or_default());
+    if rules.is_empty() {
+        bail!(
+            "Nothing to match, use --match or add rules under [watch_log.\"{}\"]",
+            args.path.display()
+        );
+    }
+    for rule in &mut rules {
+        rule.template.or_insert("title", "{{ file }}");
+        rule.template.or_insert("body", "{{ context }}");
+    }
+
+    let pusher = Pusher::new(service, encryption)?;
+    let context = rules.iter().filter_map(|rule| rule.context).max();
+    let mut before = VecDeque::new();
+    let mut pending = rules.iter().map(|_| Pending::default()).collect::<Vec<_>>();
+    // The follower reads the file with blocking calls, on a thread of its own
+    let (path, from_start) = (args.path.clone(), args.from_start);
+    let mut follower = tokio::task::spawn_blocking(move || Follower::new(path, from_start)).await?;
+    if follower.file.is_some() {
+        println!("{}: {}", "following".green(), args.path.display());
+    } else {
+        eprintln!("{}: {}", "waiting for".yellow(), args.path.display());
+    }
+
+    loop {
+        let lines;
+        (follower, lines) = tokio::task::spawn_blocking(move || {
+            let lines = follower.lines();
+            (follower, lines)
+        })
+        .await?;
+
+        for line in lines {
+            for (rule, pending) in rules.iter().zip(&mut pending) {
+                let Some(vars) = rule.matches(&args.path, &line, &before) else {
+                    continue;
+                };
+                if pending
+                    .cooldown_ends
+                    .is_some_and(|ends| Instant::now() < ends)
+                {
+                    pending.cooled += 1;
+                    continue;
+                }
+                pending.matches.push(vars);
+                match rule.batch {
+                    Some(batch) => {
+                        pending.batch_ends.get_or_insert(Instant::now() + batch);
+                    }
+                    None => push(&pusher, rule, pending).await,
+                }
+            }
+
+            if let Some(context) = context {
+                before.push_back(line);
+                if before.len() > context {
+                    before.pop_front();
+                }
+            }
+        }
+
+        for (rule, pending) in rules.iter().zip(&mut pending) {
+            if pending
+                .batch_ends
+                .is_some_and(|ends| Instant::now() >= ends)
+            {
+                pending.batch_ends = None;
+                push(&pusher, rule, pending).await;
+            }
+        }
+
+        tokio::time::sleep(POLL).await;
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anstream::{eprintln, println};
+use anyhow::{Result, bail};
+use clap::{Args, ValueEnum};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use owo_colors::OwoColorize;
+use serde::{Deserialize, de};
+use tokio::sync::mpsc;
+
+use crate::bark::{Encryption, Service};
+use crate::send::Pusher;
+use crate::template::PushTemplate;
+use crate::time::{de_option_duration, parse_duration};
+
+const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(1);
+const TICK: Duration = Duration::from_millis(200);
+
+#[derive(Args, Debug)]
+pub struct WatchPathArgs {
+    /// Directory to watch
+    dir: PathBuf,
+
+    /// Only paths that match one of these globs, relative to the directory, e.g. '*.tar.gz'
+    #[arg(long, value_name = "GLOB")]
+    glob: Vec<String>,
+
+    /// Skip paths that match one of these globs
+    #[arg(long, value_name = "GLOB")]
+    ignore: Vec<String>,
+
+    /// Kinds of events to push, default is all
+    #[arg(long, value_enum, value_delimiter = ',')]
+    events: Vec<Change>,
+
+    /// Push once a path has been quiet for this long, default is 1s
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    debounce: Option<Duration>,
+
+    /// Title template of the pushes, default is the file name and the event
+    #[arg(long, value_name = "TEMPLATE")]
+    title: Option<String>,
+
+    /// Body template of the pushes, default is the path
+    #[arg(long, value_name = "TEMPLATE")]
+    body: Option<String>,
+
+    /// Only watch the directory itself, not its subdirectories
+    #[arg(long)]
+    non_recursive: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Created,
+    Modified,
+    Deleted,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Change::Created => "created",
+            Change::Modified => "modified",
+            Change::Deleted => "deleted",
+        })
+    }
+}
+
+impl Change {
+    /// What a path went through overall, none if it came and went
+    fn then(self, next: Change) -> Option<Change> {
+        match (self, next) {
+            (Change::Created, Change::Deleted) => None,
+            (Change::Created, _) => Some(Change::Created),
+            (Change::Deleted, Change::Created) => Some(Change::Modified),
+            (_, next) => Some(next),
+        }
+    }
+}
+
+/// A rule of the `watch_path` section, under the directory it watches
+#[derive(Deserialize, Debug)]
+pub struct PathRule {
+    #[serde(default, deserialize_with = "de_globs")]
+    glob: Option<GlobSet>,
+
+    #[serde(default, deserialize_with = "de_globs")]
+    ignore: Option<GlobSet>,
+
+    /// Kinds of events, all if empty
+    #[serde(default)]
+    events: Vec<Change>,
+
+    #[serde(default, deserialize_with = "de_option_duration")]
+    debounce: Option<Duration>,
+
+    /// Devices of the push, the default devices if empty
+    #[serde(default)]
+    devices: Vec<String>,
+
+    /// Templates of push fields, variables are path, relative, file, dir and event
+    #[serde(flatten)]
+    template: PushTemplate,
+}
+
+fn globs(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        set.add(Glob::new(pattern)?);
+    }
+    Ok(Some(set.build()?))
+}
+
+fn de_globs<'de, D>(deserializer: D) -> Result<Option<GlobSet>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Patterns {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let patterns = match Patterns::deserialize(deserializer)? {
+        Patterns::One(pattern) => vec![pattern],
+        Patterns::Many(patterns) => patterns,
+    };
+    globs(&patterns).map_err(de::Error::custom)
+}
+
+impl PathRule {
+    fn from_args(args: &WatchPathArgs) -> Result<Self> {
+        let mut template = PushTemplate::default();
+        if let Some(title) = &args.title {
+            template.or_insert("title", title);
+        }
+        if let Some(body) = &args.body {
+            template.or_insert("body", body);
+        }
+        Ok(Self {
+            glob: globs(&args.glob)?,
+            ignore: globs(&args.ignore)?,
+            events: args.events.clone(),
+            debounce: args.debounce,
+            devices: Vec::new(),
+            template,
+        })
+    }
+
+    fn selects(&self, relative: &Path) -> bool {
+        self.glob.as_ref().is_none_or(|set| set.is_match(relative))
+            && !self
+                .ignore
+                .as_ref()
+                .is_some_and(|set| set.is_match(relative))
+    }
+}
+
+/// The changes of a notify event
+fn changes(event: notify::Event) -> Vec<(PathBuf, Change)> {
+    let change = match event.kind {
+        EventKind::Create(_) => Change::Created,
+        EventKind::Remove(_) => Change::Deleted,
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => Change::Deleted,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => Change::Created,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            let mut paths = event.paths.into_iter();
+            return paths
+                .next()
+                .map(|from| (from, Change::Deleted))
+                .into_iter()
+                .chain(paths.next().map(|to| (to, Change::Created)))
+                .collect();
+        }
+        EventKind::Modify(ModifyKind::Metadata(_)) | EventKind::Access(_) => return Vec::new(),
+        EventKind::Modify(_) => Change::Modified,
+        _ => return Vec::new(),
+    };
+    event.paths.into_iter().map(|path| (path, change)).collect()
+}
+
+/// A change of a path waiting to be quiet for the debounce of its rule
+struct Pending {
+    change: Option<Change>,
+    last: Instant,
+}
+
+async fn push(pusher: &Pusher, rule: &PathRule, dir: &Path, path: &Path, change: Change) {
+    let lookup = |name: &str| match name {
+        "path" => Some(path.display().to_string()),
+        "relative" => Some(path.strip_prefix(dir).unwrap_or(path).display().to_string()),
+        "file" => path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()),
+        "dir" => path.parent().map(|dir| dir.display().to_string()),
+        "event" => Some(change.to_string()),
+        _ => None,
+    };
+    let push = match rule.template.render(lookup) {
+        Ok(push) => push,
+        Err(er) => {
+            eprintln!("{}: {}", "watch-path rule".red(), er);
+            return;
+        }
+    };
+    match pusher.push(&push, &rule.devices).await {
+        Ok(()) => println!("{}: {}", "pushed".green(), push.label()),
+        Err(er) => eprintln!("{}: {}", "push failed".red(), er),
+    }
+}
+
+pub async fn watch(
+    args: &WatchPathArgs,
+    mut config: HashMap<String, Vec<PathRule>>,
+    service: Service,
+    encryption: Encryption,
+) -> Result<()> {
+    let key = args.dir.to_string_lossy();
+    let configured = config.remove(key.as_ref()).unwrap_or_default();
+    let mut rules = Vec::new();
+    if configured.is_empty()
+        || !args.glob.is_empty()
+        || !args.ignore.is_empty()
+        || !args.events.is_empty()
+        || args.title.is_some()
+        || args.body.is_some()
+    {
+        rules.push(PathRule::from_args(args)?);
+    }
+    rules.extend(configured);
+    for rule in &mut rules {
+        rule.template.or_insert("title", "{{ file }} {{ event }}");
+        rule.template.or_insert("body", "{{ path }}");
+    }
+
+    let dir = tokio::fs::canonicalize(&args.dir).await?;
+    if !tokio::fs::metadata(&dir).await?.is_dir() {
+        bail!("{} is not a directory", args.dir.display());
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mode = if args.non_recursive {
+        RecursiveMode::NonRecursive
+    } else {
+        RecursiveMode::Recursive
+    };
+    // Watching a tree walks all of it
+    let watched = dir.clone();
+    let _watcher = tokio::task::spawn_blocking(move || {
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+        watcher.watch(&watched, mode)?;
+        anyhow::Ok(watcher)
+    })
+    .await??;
+    println!("{}: {}", "watching".green(), dir.display());
+
+    let pusher = Pusher::new(service, encryption)?;
+    let mut pending = HashMap::<(usize, PathBuf), Pending>::new();
+    loop {
+        match tokio::time::timeout(TICK, rx.recv()).await {
+            Ok(None) => bail!("The watcher stopped"),
+            Ok(Some(Err(er))) => eprintln!("{}: {}", "watch error".red(), er),
+            Ok(Some(Ok(event))) => {
+                for (path, change) in changes(event) {
+                    let Ok(relative) = path.strip_prefix(&dir) else {
+                        continue;
+                    };
+                    if relative.as_os_str().is_empty() {
+                        continue;
+                    }
+                    for (i, rule) in rules.iter().enumerate() {
+                        if !rule.selects(relative) {
+                            continue;
+                        }
+                        pending
+                            .entry((i, path.clone()))
+                            .and_modify(|pending| {
+                                pending.change = match pending.change {
+                                    Some(before) => before.then(change),
+                                    None => Some(change),
+                                };
+                                pending.last = Instant::now();
+                            })
+                            .or_insert(Pending {
+                                change: Some(change),
+                                last: Instant::now(),
+                            });
+                    }
+                }
+            }
+            Err(_) => {}
+        }
+
+        let quiet = pending
+            .iter()
+            .filter(|((i, _), pending)| {
+                pending.last.elapsed() >= rules[*i].debounce.unwrap_or(DEFAULT_DEBOUNCE)
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in quiet {
+            let Some(Pending {
+                change: Some(change),
+                ..
+            }) = pending.remove(&key)
+            else {
+                continue;
+            };
+            let (i, path) = key;
+            let rule = &rules[i];
+            if rule.events.is_empty() || rule.events.contains(&change) {
+                push(&pusher, rule, &dir, &path, change).await;
+            }
+        }
+    }
+}